@@ -0,0 +1,94 @@
+//! Benchmarks the encoding of the messages sent by the client.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use phoenix_chan::__bench::{encode_binary, encode_json};
+use phoenix_chan::Map;
+use serde::Serialize;
+use tungstenite::Utf8Bytes;
+
+const TOPIC: &str = "room:lobby";
+const EVENT: &str = "new_msg";
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    body: &'a str,
+    count: u64,
+}
+
+/// Encoding with the references allocated as strings and a new frame for every message.
+fn allocating<P>(msg_id: usize, payload: P) -> Utf8Bytes
+where
+    P: Serialize,
+{
+    let msg = (
+        Some(1.to_string()),
+        Some(msg_id.to_string()),
+        TOPIC,
+        EVENT,
+        payload,
+    );
+
+    serde_json::to_string(&msg).unwrap().into()
+}
+
+fn small_push(c: &mut Criterion) {
+    let payload = Payload {
+        body: "hello",
+        count: 42,
+    };
+
+    let mut group = c.benchmark_group("small_push");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("allocating", |b| {
+        let mut msg_id = 0usize;
+
+        b.iter(|| {
+            msg_id = msg_id.wrapping_add(1);
+
+            black_box(allocating(msg_id, black_box(&payload)))
+        })
+    });
+
+    // The client reuses the same buffer for every message
+    group.bench_function("json", |b| {
+        let mut buf = Vec::new();
+        let mut msg_id = 0usize;
+
+        b.iter(|| {
+            msg_id = msg_id.wrapping_add(1);
+
+            black_box(encode_json(&mut buf, msg_id, TOPIC, EVENT, black_box(&payload)).unwrap())
+        })
+    });
+
+    group.bench_function("json_empty_map", |b| {
+        let payload = Map::default();
+        let mut buf = Vec::new();
+        let mut msg_id = 0usize;
+
+        b.iter(|| {
+            msg_id = msg_id.wrapping_add(1);
+
+            black_box(encode_json(&mut buf, msg_id, TOPIC, EVENT, black_box(&payload)).unwrap())
+        })
+    });
+
+    group.bench_function("binary", |b| {
+        let payload = br#"{"body":"hello","count":42}"#;
+        let mut msg_id = 0usize;
+
+        b.iter(|| {
+            msg_id = msg_id.wrapping_add(1);
+
+            black_box(encode_binary(msg_id, TOPIC, EVENT, black_box(payload)).unwrap())
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, small_push);
+criterion_main!(benches);
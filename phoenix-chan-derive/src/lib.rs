@@ -0,0 +1,312 @@
+//! Derive macros for the [`phoenix-chan`](https://docs.rs/phoenix-chan) crate.
+
+#![warn(
+    missing_docs,
+    rustdoc::missing_crate_level_docs,
+    clippy::todo,
+    rustdoc::broken_intra_doc_links
+)]
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    Data, DeriveInput, Fields, Generics, Ident, LitStr, TypeParamBound, Variant, parse_macro_input,
+    parse_quote,
+};
+
+/// Derives `IntoEvent` and `FromEvent` for an enum, with a variant for each event.
+///
+/// The event name of a variant is set with the `#[event("name")]` attribute, or defaults to the
+/// name of the variant in snake case. The payload of the event depends on the variant:
+///
+/// - unit variants send an empty object and ignore the received payload;
+/// - newtype variants serialize the field as the payload;
+/// - struct variants serialize the fields as the keys of a payload object.
+#[proc_macro_derive(PhoenixEvent, attributes(event))]
+pub fn derive_phoenix_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            input,
+            "PhoenixEvent can only be derived for enums",
+        ));
+    };
+
+    let variants = data
+        .variants
+        .iter()
+        .map(EventVariant::parse)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    for (i, variant) in variants.iter().enumerate() {
+        if let Some(other) = variants[..i].iter().find(|v| v.event == variant.event) {
+            return Err(syn::Error::new_spanned(
+                &variant.ident,
+                format!(
+                    "the event \"{}\" is already used by the variant {}",
+                    variant.event, other.ident
+                ),
+            ));
+        }
+    }
+
+    let ident = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+
+    let into_generics = bound_params(
+        &input.generics,
+        &parse_quote!(::phoenix_chan::__private::serde::Serialize),
+    );
+    let (into_impl_generics, _, into_where_clause) = into_generics.split_for_impl();
+    let from_generics = bound_params(
+        &input.generics,
+        &parse_quote!(::phoenix_chan::__private::serde::de::DeserializeOwned),
+    );
+    let (from_impl_generics, _, from_where_clause) = from_generics.split_for_impl();
+
+    let encode_arms = variants.iter().map(EventVariant::encode_arm);
+    let decode_arms = variants.iter().map(EventVariant::decode_arm);
+
+    Ok(quote! {
+        impl #into_impl_generics ::phoenix_chan::event::IntoEvent for #ident #ty_generics
+            #into_where_clause
+        {
+            fn into_event(
+                self,
+            ) -> ::std::result::Result<
+                (&'static str, ::phoenix_chan::serde_json::Value),
+                ::phoenix_chan::serde_json::Error,
+            > {
+                match self {
+                    #(#encode_arms)*
+                }
+            }
+        }
+
+        impl #from_impl_generics ::phoenix_chan::event::FromEvent for #ident #ty_generics
+            #from_where_clause
+        {
+            #[allow(unused_variables)]
+            fn from_event(
+                event: &str,
+                payload: ::phoenix_chan::serde_json::Value,
+            ) -> ::std::result::Result<Self, ::phoenix_chan::Error> {
+                match event {
+                    #(#decode_arms)*
+                    _ => ::std::result::Result::Err(::phoenix_chan::Error::UnknownEvent(
+                        ::std::string::ToString::to_string(event),
+                    )),
+                }
+            }
+        }
+    })
+}
+
+/// Adds the bound to every type parameter, since they are serialized in the payload.
+fn bound_params(generics: &Generics, bound: &TypeParamBound) -> Generics {
+    let mut generics = generics.clone();
+
+    let params: Vec<_> = generics.type_params().map(|p| p.ident.clone()).collect();
+
+    let where_clause = generics.make_where_clause();
+
+    for param in params {
+        where_clause.predicates.push(parse_quote!(#param: #bound));
+    }
+
+    generics
+}
+
+struct EventVariant {
+    ident: Ident,
+    event: String,
+    fields: Fields,
+}
+
+impl EventVariant {
+    fn parse(variant: &Variant) -> syn::Result<Self> {
+        let mut event = None;
+
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("event")) {
+            if event.is_some() {
+                return Err(syn::Error::new_spanned(attr, "duplicate event attribute"));
+            }
+
+            event = Some(attr.parse_args::<LitStr>()?.value());
+        }
+
+        match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() != 1 => {
+                return Err(syn::Error::new_spanned(
+                    fields,
+                    "tuple variants must have a single field with the payload",
+                ));
+            }
+            Fields::Unit | Fields::Unnamed(_) | Fields::Named(_) => {}
+        }
+
+        Ok(Self {
+            ident: variant.ident.clone(),
+            event: event.unwrap_or_else(|| snake_case(&variant.ident.to_string())),
+            fields: variant.fields.clone(),
+        })
+    }
+
+    fn encode_arm(&self) -> TokenStream2 {
+        let Self {
+            ident,
+            event,
+            fields,
+        } = self;
+
+        match fields {
+            Fields::Unit => quote! {
+                Self::#ident => ::std::result::Result::Ok((
+                    #event,
+                    ::phoenix_chan::serde_json::Value::Object(
+                        ::phoenix_chan::serde_json::Map::new(),
+                    ),
+                )),
+            },
+            Fields::Unnamed(_) => quote! {
+                Self::#ident(payload) => ::phoenix_chan::serde_json::to_value(payload)
+                    .map(|payload| (#event, payload)),
+            },
+            Fields::Named(named) => {
+                let names: Vec<_> = named
+                    .named
+                    .iter()
+                    .filter_map(|f| f.ident.as_ref())
+                    .collect();
+                let keys = names.iter().map(|n| n.to_string());
+                // Doesn't conflict with the name of the fields
+                let payload = format_ident!("payload", span = Span::mixed_site());
+
+                quote! {
+                    Self::#ident { #(#names),* } => {
+                        let mut #payload = ::phoenix_chan::serde_json::Map::new();
+
+                        #(
+                            #payload.insert(
+                                ::std::string::ToString::to_string(#keys),
+                                ::phoenix_chan::serde_json::to_value(#names)?,
+                            );
+                        )*
+
+                        ::std::result::Result::Ok((
+                            #event,
+                            ::phoenix_chan::serde_json::Value::Object(#payload),
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode_arm(&self) -> TokenStream2 {
+        let Self {
+            ident,
+            event,
+            fields,
+        } = self;
+
+        match fields {
+            Fields::Unit => quote! {
+                #event => ::std::result::Result::Ok(Self::#ident),
+            },
+            Fields::Unnamed(_) => quote! {
+                #event => ::phoenix_chan::serde_json::from_value(payload)
+                    .map(Self::#ident)
+                    .map_err(::phoenix_chan::Error::Deserialize),
+            },
+            Fields::Named(named) => {
+                let names: Vec<_> = named
+                    .named
+                    .iter()
+                    .filter_map(|f| f.ident.as_ref())
+                    .collect();
+                let keys = names.iter().map(|n| n.to_string());
+                // Doesn't conflict with the name of the fields
+                let map = format_ident!("map", span = Span::mixed_site());
+
+                quote! {
+                    #event => {
+                        let mut #map: ::phoenix_chan::serde_json::Map<
+                            ::std::string::String,
+                            ::phoenix_chan::serde_json::Value,
+                        > = ::phoenix_chan::serde_json::from_value(payload)
+                            .map_err(::phoenix_chan::Error::Deserialize)?;
+
+                        ::std::result::Result::Ok(Self::#ident {
+                            #(
+                                #names: match #map.remove(#keys) {
+                                    ::std::option::Option::Some(value) => {
+                                        ::phoenix_chan::serde_json::from_value(value)
+                                    }
+                                    ::std::option::Option::None => {
+                                        ::phoenix_chan::__private::missing_field(#keys)
+                                    }
+                                }
+                                .map_err(::phoenix_chan::Error::Deserialize)?,
+                            )*
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Converts the variant name from camel case to snake case.
+///
+/// A run of capitals is a single word, so `HTTPRequest` becomes `http_request`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::with_capacity(name.len() + 4);
+
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let prev = i.checked_sub(1).map(|i| chars[i]);
+            let next = chars.get(i + 1);
+
+            let word_start = match prev {
+                Some(prev) if prev.is_uppercase() => next.is_some_and(|n| n.is_lowercase()),
+                Some(prev) => prev != '_',
+                None => false,
+            };
+
+            if word_start {
+                out.push('_');
+            }
+
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variant_name_to_snake_case() {
+        assert_eq!(snake_case("NewMsg"), "new_msg");
+        assert_eq!(snake_case("Ping"), "ping");
+        assert_eq!(snake_case("presence_diff"), "presence_diff");
+        assert_eq!(snake_case("HTTPRequest"), "http_request");
+        assert_eq!(snake_case("UserID"), "user_id");
+        assert_eq!(snake_case("Msg2Fa"), "msg2_fa");
+    }
+}
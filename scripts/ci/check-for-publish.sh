@@ -10,9 +10,9 @@ listPackage() {
 }
 
 pkgsFiles=$(
-    # Add <(listPackage "@OTHER_CRATE@") \
     cat \
-        <(cargo package --allow-dirty -l -p "phoenix-chan") |
+        <(cargo package --allow-dirty -l -p "phoenix-chan") \
+        <(listPackage "phoenix-chan-derive") |
         sort
 )
 localFiles=$(
@@ -1,18 +1,32 @@
 //! Configures a [`Client`]
 
+use std::future::Future;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
-use async_tungstenite::tokio::connect_async_with_tls_connector_and_config;
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tokio::TokioAdapter;
 use base64::Engine;
+use futures::FutureExt;
+use futures::future::BoxFuture;
 use rustls::ClientConfig;
-use tokio_rustls::TlsConnector;
-use tracing::trace;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, trace};
 use tungstenite::ClientRequestBuilder;
-use tungstenite::http::Uri;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::{Request, Response};
 use tungstenite::http::uri::PathAndQuery;
+use tungstenite::http::{HeaderMap, Uri, header};
 use tungstenite::protocol::WebSocketConfig;
 
+use crate::client::Handshake;
+use crate::connect::{self, BoxIo, Dialer, ProxyConfig};
+use crate::error::TokenError;
+use crate::longpoll::LongPoll;
+use crate::proxy::Proxy;
+use crate::transport::WebSocket;
 use crate::{Client, Error};
 
 /// Authentication token prefix
@@ -20,21 +34,77 @@ use crate::{Client, Error};
 /// See <https://github.com/phoenixframework/phoenix/blob/ad1a7ee2c9c29ff102b94242fdbb9cb14dd0dd4b/assets/js/phoenix/constants.js#L30>
 const AUTH_TOKEN_PREFIX: &str = "base64url.bearer.phx.";
 
+/// Sub-protocol selected by the server when the authentication token is passed.
+const PHOENIX_PROTOCOL: &str = "phoenix";
+
 const BASE_64: base64::engine::GeneralPurpose = base64::prelude::BASE64_URL_SAFE_NO_PAD;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
-const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(DEFAULT_TIMEOUT.as_secs() / 2);
+pub(crate) const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(DEFAULT_TIMEOUT.as_secs() / 2);
 
 /// Builder to configure a [`Client`]
 #[derive(Debug)]
 pub struct Builder {
     client_req: ClientRequestBuilder,
     ws_config: WebSocketConfig,
-    tls_config: Option<Arc<ClientConfig>>,
-    auth_token: Option<String>,
+    sub_protocols: Vec<String>,
+    auth_token: Option<AuthToken>,
+    params: Option<Params>,
+    dialer: Dialer,
+    long_poll: LongPollMode,
+    /// Consecutive failed WebSocket connections, for the long-poll fallback.
+    ws_failures: AtomicUsize,
     heartbeat: Duration,
 }
 
+/// When to connect with the long-poll transport.
+#[derive(Debug, Clone, Copy, Default)]
+enum LongPollMode {
+    /// Always connect with the WebSocket.
+    #[default]
+    Never,
+    /// Always connect with the long-poll transport.
+    Always,
+    /// Connect with the long-poll transport after the WebSocket failed the number of times.
+    Fallback(usize),
+}
+
+/// Token passed to the server in the `Sec-WebSocket-Protocol` header, or in the
+/// `X-Phoenix-AuthToken` header with the long-poll transport.
+enum AuthToken {
+    Static(String),
+    Provider(Box<dyn Fn() -> BoxFuture<'static, Result<String, TokenError>> + Send + Sync>),
+}
+
+impl AuthToken {
+    /// Returns the token for the next connection.
+    async fn get(&self) -> Result<String, Error> {
+        match self {
+            AuthToken::Static(token) => Ok(token.clone()),
+            AuthToken::Provider(provider) => provider().await.map_err(Error::AuthToken),
+        }
+    }
+}
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // Don't leak the token in the logs
+            Self::Static(_) => f.debug_tuple("Static").finish_non_exhaustive(),
+            Self::Provider(_) => f.debug_tuple("Provider").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Encodes the connect params in the query of the URI.
+struct Params(Box<dyn Fn() -> Result<String, serde_urlencoded::ser::Error> + Send + Sync>);
+
+impl std::fmt::Debug for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Params").finish_non_exhaustive()
+    }
+}
+
 impl Builder {
     /// Returns a new instance with defaults set.
     pub fn new(mut uri: Uri) -> Result<Self, Error> {
@@ -43,28 +113,20 @@ impl Builder {
             .is_some_and(|s| s.split('&').any(|s| s.starts_with("vsn=")));
 
         if !has_vsn {
-            let pq = match uri.query() {
-                Some(query) if !query.is_empty() => {
-                    PathAndQuery::try_from(format!("{}?{query}&vsn=2.0.0", uri.path()))
-                        .map_err(Error::Uri)?
-                }
-                Some(_) | None => PathAndQuery::try_from(format!("{}?vsn=2.0.0", uri.path()))
-                    .map_err(Error::Uri)?,
-            };
-
-            uri = tungstenite::http::uri::Builder::from(uri)
-                .path_and_query(pq)
-                .build()
-                .map_err(Error::UriBuild)?;
+            uri = append_query(&uri, "vsn=2.0.0")?;
         }
 
-        let client_req = ClientRequestBuilder::new(uri.clone());
+        let client_req = ClientRequestBuilder::new(uri);
 
         Ok(Self {
             client_req,
             ws_config: WebSocketConfig::default(),
-            tls_config: None,
+            sub_protocols: Vec::new(),
             auth_token: None,
+            params: None,
+            dialer: Dialer::default(),
+            long_poll: LongPollMode::default(),
+            ws_failures: AtomicUsize::new(0),
             // https://github.com/phoenixframework/phoenix/blob/ad1a7ee2c9c29ff102b94242fdbb9cb14dd0dd4b/assets/js/phoenix/constants.js#L6
             heartbeat: DEFAULT_HEARTBEAT,
         })
@@ -86,7 +148,10 @@ impl Builder {
         self
     }
 
-    /// Add a sub-protocol header to the WebSocket connection.
+    /// Add a header to the client connection request.
+    ///
+    /// Despite the name, this doesn't add a sub-protocol to the `Sec-WebSocket-Protocol` header.
+    #[deprecated(note = "use `add_header` or `sub_protocol` instead")]
     #[must_use]
     pub fn add_sub_protocol(mut self, key: String, value: String) -> Self {
         self.client_req = self.client_req.with_header(key, value);
@@ -94,14 +159,71 @@ impl Builder {
         self
     }
 
+    /// Add a sub-protocol to offer to the server in the `Sec-WebSocket-Protocol` header.
+    ///
+    /// The sub-protocols are offered in the order they are added, before the ones of the
+    /// authentication token. The connection fails if the server selects a sub-protocol that
+    /// wasn't offered, or one other than `phoenix` when the token is sent.
+    #[must_use]
+    pub fn sub_protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.sub_protocols.push(protocol.into());
+
+        self
+    }
+
     /// Set the authentication token to pass to the server.
     #[must_use]
     pub fn auth_token(mut self, token: &str) -> Self {
-        let encoded = BASE_64.encode(token);
+        self.auth_token = Some(AuthToken::Static(token.to_string()));
+
+        self
+    }
+
+    /// Set a provider called for the authentication token before every connection.
+    ///
+    /// If the server rejects the upgrade with `401 Unauthorized` or `403 Forbidden`, the provider
+    /// is called again to refresh the token and the connection is retried once.
+    #[must_use]
+    pub fn auth_token_provider<F, Fut>(mut self, provider: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, TokenError>> + Send + 'static,
+    {
+        self.auth_token = Some(AuthToken::Provider(Box::new(move || provider().boxed())));
+
+        self
+    }
 
-        self.auth_token = Some(format!("{AUTH_TOKEN_PREFIX}{encoded}"));
+    /// Set the params passed to the server in the query of the URI, like the `params` of the
+    /// phoenix.js `Socket`.
+    ///
+    /// The params are URL-encoded alongside the `vsn`, so they must serialize to a map or struct
+    /// of scalar values.
+    #[must_use]
+    pub fn params<P>(mut self, params: P) -> Self
+    where
+        P: Serialize + Send + Sync + 'static,
+    {
+        self.params = Some(Params(Box::new(move || {
+            serde_urlencoded::to_string(&params)
+        })));
+
+        self
+    }
 
-        self.client_req = self.client_req.with_sub_protocol("phoenix");
+    /// Set a function returning the params passed to the server in the query of the URI.
+    ///
+    /// The function is called before every connection, to pass values that change between
+    /// connections like rotating tokens or timestamps. See [`Builder::params`].
+    #[must_use]
+    pub fn params_with<F, P>(mut self, params: F) -> Self
+    where
+        F: Fn() -> P + Send + Sync + 'static,
+        P: Serialize,
+    {
+        self.params = Some(Params(Box::new(move || {
+            serde_urlencoded::to_string(params())
+        })));
 
         self
     }
@@ -109,7 +231,107 @@ impl Builder {
     /// Configure the [`WebSocketConfig`]
     #[must_use]
     pub fn tls_config(mut self, tls_config: Arc<ClientConfig>) -> Self {
-        self.tls_config = Some(tls_config);
+        self.dialer.tls_config = Some(tls_config);
+
+        self
+    }
+
+    /// Connect to the server through the proxy.
+    #[must_use]
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.dialer.proxy = ProxyConfig::Proxy(proxy);
+
+        self
+    }
+
+    /// Connect through the proxy in the `HTTPS_PROXY` or `HTTP_PROXY` environment variables,
+    /// depending on the scheme of the URI, unless the host is listed in `NO_PROXY`.
+    ///
+    /// The variables are read before every connection. The proxy URI must have the `http`,
+    /// `socks5` or `socks5h` scheme, a TLS connection to the proxy is not supported.
+    #[must_use]
+    pub fn proxy_from_env(mut self) -> Self {
+        self.dialer.proxy = ProxyConfig::Env;
+
+        self
+    }
+
+    /// Connect to the server through the Unix domain socket at the path.
+    ///
+    /// The `Host` header and the path of the upgrade request are still taken from the URI, while
+    /// the proxy and TLS options are not used.
+    #[cfg(unix)]
+    #[must_use]
+    pub fn unix_socket(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.dialer.unix_socket = Some(path.into());
+
+        self
+    }
+
+    /// Set the timeout to resolve the host and open the connection, including the tunnel through
+    /// the proxy.
+    ///
+    /// By default the connection waits indefinitely.
+    #[must_use]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.dialer.timeouts.connect = Some(timeout);
+
+        self
+    }
+
+    /// Set the timeout of the TLS handshake.
+    ///
+    /// By default the handshake waits indefinitely.
+    #[must_use]
+    pub fn tls_timeout(mut self, timeout: Duration) -> Self {
+        self.dialer.timeouts.tls = Some(timeout);
+
+        self
+    }
+
+    /// Set the timeout of the WebSocket upgrade request.
+    ///
+    /// By default the upgrade waits indefinitely.
+    #[must_use]
+    pub fn upgrade_timeout(mut self, timeout: Duration) -> Self {
+        self.dialer.timeouts.upgrade = Some(timeout);
+
+        self
+    }
+
+    /// Set the timeout of each request of the long-poll transport.
+    ///
+    /// The timeout includes the time the server holds a poll waiting for the messages, so it must
+    /// be longer than the poll window of the server. By default it's 20 seconds, like phoenix.js.
+    #[must_use]
+    pub fn long_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.dialer.timeouts.long_poll = Some(timeout);
+
+        self
+    }
+
+    /// Connect with the long-poll transport instead of the WebSocket.
+    ///
+    /// Like phoenix.js, the messages are received by polling the `longpoll` endpoint of the
+    /// socket, that replaces the `websocket` segment at the end of the path, and are sent in
+    /// batches with `POST` requests. The sub-protocols and WebSocket options are not used, and
+    /// binary messages cannot be sent.
+    #[must_use]
+    pub fn long_poll(mut self) -> Self {
+        self.long_poll = LongPollMode::Always;
+
+        self
+    }
+
+    /// Fall back to the long-poll transport after the WebSocket failed to connect the number of
+    /// consecutive attempts, for networks where the upgrade is blocked.
+    ///
+    /// The connections rejected for the authentication are not counted. After falling back, the
+    /// next connections of the builder also use the long-poll transport. See
+    /// [`Builder::long_poll`].
+    #[must_use]
+    pub fn long_poll_fallback(mut self, attempts: usize) -> Self {
+        self.long_poll = LongPollMode::Fallback(attempts);
 
         self
     }
@@ -123,24 +345,518 @@ impl Builder {
     }
 
     /// Returns a configured client.
-    pub async fn connect(mut self) -> Result<Client, Error> {
-        if let Some(token) = self.auth_token {
-            self.client_req = self.client_req.with_sub_protocol(token);
+    ///
+    /// The builder can be used to connect again, for example after the client disconnected.
+    pub async fn connect(&self) -> Result<Client, Error> {
+        let Some(auth_token) = &self.auth_token else {
+            return self.connect_with_token(None).await;
+        };
+
+        let token = auth_token.get().await?;
+
+        match self.connect_with_token(Some(token)).await {
+            Err(err) if err.is_auth_rejected() && matches!(auth_token, AuthToken::Provider(_)) => {
+                debug!("authentication rejected, refreshing the token");
+
+                let token = auth_token.get().await?;
+
+                self.connect_with_token(Some(token)).await
+            }
+            res => res,
         }
+    }
+
+    /// Upgrades the connection on the stream to a WebSocket.
+    ///
+    /// The stream is already connected to the server, like a Unix domain socket or a connection
+    /// established through custom networking, so the proxy, TLS and connect options are not used.
+    /// The `Host` header and path of the request are taken from the URI.
+    pub async fn connect_with_stream<S>(&self, stream: S) -> Result<Client, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let token = match &self.auth_token {
+            Some(auth_token) => Some(auth_token.get().await?),
+            None => None,
+        };
 
-        let connector = self.tls_config.map(TlsConnector::from);
+        let (request, offered) = self.request(token.as_deref())?;
+        let with_token = token.is_some();
 
-        let (connection, resp) = connect_async_with_tls_connector_and_config(
-            self.client_req,
-            connector,
-            Some(self.ws_config),
+        let res = connect::upgrade(
+            request,
+            Box::new(TokioAdapter::new(stream)),
+            self.ws_config,
+            self.dialer.timeouts.upgrade,
         )
-        .await
-        .map_err(Box::new)
-        .map_err(Error::Connect)?;
+        .await;
+
+        self.client(res, offered, with_token)
+    }
+
+    async fn connect_with_token(&self, token: Option<String>) -> Result<Client, Error> {
+        let attempts = match self.long_poll {
+            LongPollMode::Never => return self.connect_websocket(token).await,
+            LongPollMode::Always => return self.connect_long_poll(token).await,
+            LongPollMode::Fallback(attempts) => attempts,
+        };
+
+        if self.ws_failures.load(Ordering::Acquire) >= attempts {
+            return self.connect_long_poll(token).await;
+        }
+
+        match self.connect_websocket(token.clone()).await {
+            Ok(client) => {
+                self.ws_failures.store(0, Ordering::Release);
+
+                Ok(client)
+            }
+            Err(err) if err.is_auth_rejected() => Err(err),
+            Err(err) => {
+                let failures = self.ws_failures.fetch_add(1, Ordering::AcqRel) + 1;
+
+                if failures < attempts {
+                    return Err(err);
+                }
+
+                debug!(failures, error = %err, "WebSocket failed, falling back to long-poll");
+
+                self.connect_long_poll(token).await
+            }
+        }
+    }
+
+    async fn connect_websocket(&self, token: Option<String>) -> Result<Client, Error> {
+        let (request, offered) = self.request(token.as_deref())?;
+
+        let res = self.dialer.connect(request, self.ws_config).await;
+
+        self.client(res, offered, token.is_some())
+    }
+
+    async fn connect_long_poll(&self, token: Option<String>) -> Result<Client, Error> {
+        let (request, _offered) = self.request(None)?;
+
+        let (parts, ()) = request.into_parts();
+
+        // Keep only the headers added by the user
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &parts.headers {
+            let upgrade = name == header::HOST
+                || name == header::CONNECTION
+                || name == header::UPGRADE
+                || name.as_str().starts_with("sec-websocket-");
+
+            if !upgrade {
+                headers.append(name, value.clone());
+            }
+        }
+
+        let (session, handshake) =
+            LongPoll::open(self.dialer.clone(), &parts.uri, headers, token.as_deref()).await?;
+
+        trace!(status = %handshake.status(), headers = ?handshake.headers());
+
+        Ok(Client::new(session, handshake, self.heartbeat))
+    }
+
+    /// Returns the upgrade request and the sub-protocols offered to the server.
+    fn request(&self, token: Option<&str>) -> Result<(Request, Vec<String>), Error> {
+        let mut client_req = self.client_req.clone();
+
+        // The token is never selected, only the other sub-protocols are accepted
+        let mut offered = self.sub_protocols.clone();
+
+        if token.is_some() {
+            offered.push(PHOENIX_PROTOCOL.to_string());
+        }
+
+        for protocol in offered.iter().cloned().chain(token.map(encode_token)) {
+            client_req = client_req.with_sub_protocol(protocol);
+        }
+
+        let mut request = client_req
+            .into_client_request()
+            .map_err(Box::new)
+            .map_err(Error::Connect)?;
+
+        if let Some(Params(params)) = &self.params {
+            let params = params().map_err(Error::Params)?;
+
+            *request.uri_mut() = append_query(request.uri(), &params)?;
+        }
+
+        Ok((request, offered))
+    }
+
+    /// Checks the result of the upgrade and returns the client.
+    fn client(
+        &self,
+        res: Result<(WebSocketStream<BoxIo>, Response), Error>,
+        offered: Vec<String>,
+        with_token: bool,
+    ) -> Result<Client, Error> {
+        let (connection, resp) = match res {
+            Err(Error::SubProtocol { selected, .. }) => {
+                return Err(Error::SubProtocol { offered, selected });
+            }
+            res => res?,
+        };
+
+        let handshake = Handshake::new(resp);
+
+        trace!(status = %handshake.status(), headers = ?handshake.headers());
+
+        if !is_accepted(&offered, handshake.protocol(), with_token) {
+            return Err(Error::SubProtocol {
+                offered,
+                selected: handshake.protocol().map(str::to_string),
+            });
+        }
+
+        Ok(Client::new(
+            WebSocket::new(connection),
+            handshake,
+            self.heartbeat,
+        ))
+    }
+}
+
+/// Returns true if the selected sub-protocol was offered, or if none was offered.
+///
+/// When the authentication token is sent the server must select the `phoenix` sub-protocol,
+/// otherwise the token wasn't used.
+fn is_accepted(offered: &[String], selected: Option<&str>, with_token: bool) -> bool {
+    if with_token {
+        return selected == Some(PHOENIX_PROTOCOL);
+    }
+
+    match selected {
+        Some(selected) => offered.iter().any(|p| p == selected),
+        None => offered.is_empty(),
+    }
+}
+
+/// Encodes the token in the sub-protocol format expected by Phoenix.
+fn encode_token(token: &str) -> String {
+    let encoded = BASE_64.encode(token);
+
+    format!("{AUTH_TOKEN_PREFIX}{encoded}")
+}
+
+/// Appends the URL-encoded parameters to the query of the URI.
+pub(crate) fn append_query(uri: &Uri, params: &str) -> Result<Uri, Error> {
+    if params.is_empty() {
+        return Ok(uri.clone());
+    }
+
+    let pq = match uri.query() {
+        Some(query) if !query.is_empty() => format!("{}?{query}&{params}", uri.path()),
+        Some(_) | None => format!("{}?{params}", uri.path()),
+    };
+
+    let pq = PathAndQuery::try_from(pq).map_err(Error::Uri)?;
+
+    tungstenite::http::uri::Builder::from(uri.clone())
+        .path_and_query(pq)
+        .build()
+        .map_err(Error::UriBuild)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::error::ConnectStep;
+
+    // The error response of the upgrade callback is defined by tungstenite
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn connect_with_duplex_stream() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let mut ws = async_tungstenite::tokio::accept_hdr_async(
+                server,
+                |req: &tungstenite::handshake::server::Request,
+                 mut resp: tungstenite::handshake::server::Response| {
+                    assert_eq!(req.uri().to_string(), "/socket/websocket?vsn=2.0.0&user=42");
+
+                    resp.headers_mut().insert(
+                        tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL,
+                        PHOENIX_PROTOCOL.parse().unwrap(),
+                    );
+
+                    Ok(resp)
+                },
+            )
+            .await
+            .unwrap();
+
+            futures::StreamExt::next(&mut ws).await.unwrap().unwrap()
+        });
+
+        let client = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .params([("user", "42")])
+            .auth_token("token")
+            .connect_with_stream(client)
+            .await
+            .unwrap();
+
+        assert_eq!(client.handshake().protocol(), Some(PHOENIX_PROTOCOL));
+
+        client.send("room:lobby", "ping", ()).await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap().into_text().unwrap().as_str(),
+            r#"["1","1","room:lobby","ping",null]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_frames_sent_with_the_upgrade_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let mut req = Vec::new();
+
+            while !req.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                server.read_exact(&mut byte).await.unwrap();
+                req.push(byte[0]);
+            }
+
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut parsed = httparse::Request::new(&mut headers);
+            parsed.parse(&req).unwrap();
+
+            let key = parsed
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("sec-websocket-key"))
+                .unwrap()
+                .value;
+            let accept = tungstenite::handshake::derive_accept_key(key);
+
+            let frame = br#"[null,null,"room:lobby","new_msg",{}]"#;
+
+            // The first frame is written together with the response
+            let mut resp = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .into_bytes();
+            resp.extend_from_slice(&[0x81, u8::try_from(frame.len()).unwrap()]);
+            resp.extend_from_slice(frame);
+
+            server.write_all(&resp).await.unwrap();
+
+            server
+        });
+
+        let client = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .connect_with_stream(client)
+            .await
+            .unwrap();
+
+        let msg = client.recv::<serde_json::Value>().await.unwrap();
+
+        assert_eq!(msg.event_name, "new_msg");
+
+        drop(server.await.unwrap());
+    }
+
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn return_the_unexpected_sub_protocol() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            // The client closes the connection after the upgrade
+            let _ = async_tungstenite::tokio::accept_hdr_async(
+                server,
+                |_req: &tungstenite::handshake::server::Request,
+                 mut resp: tungstenite::handshake::server::Response| {
+                    resp.headers_mut().insert(
+                        tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL,
+                        "v2.json".parse().unwrap(),
+                    );
+
+                    Ok(resp)
+                },
+            )
+            .await;
+        });
+
+        let res = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .sub_protocol("v1.json")
+            .connect_with_stream(client)
+            .await;
+
+        server.await.unwrap();
+
+        let Err(Error::SubProtocol { offered, selected }) = res else {
+            panic!("expected a sub-protocol error, got {res:?}");
+        };
+
+        assert_eq!(offered, ["v1.json"]);
+        assert_eq!(selected.as_deref(), Some("v2.json"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connect_with_unix_socket() {
+        let path = std::env::temp_dir().join(format!("phoenix-chan-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let mut ws = async_tungstenite::tokio::accept_async(stream)
+                .await
+                .unwrap();
+
+            futures::StreamExt::next(&mut ws).await.unwrap().unwrap()
+        });
+
+        let client = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .unix_socket(&path)
+            .connect()
+            .await
+            .unwrap();
+
+        client.send("room:lobby", "ping", ()).await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap().into_text().unwrap().as_str(),
+            r#"["1","1","room:lobby","ping",null]"#
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Accepts the connections and never responds.
+    async fn silent_listener() -> (std::net::SocketAddr, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn connect_steps_time_out() {
+        let (addr, server) = silent_listener().await;
+        let timeout = Duration::from_millis(50);
+
+        let proxy = Proxy::http(&format!("http://{addr}").parse().unwrap()).unwrap();
+        let res = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .proxy(proxy)
+            .connect_timeout(timeout)
+            .connect()
+            .await;
+
+        assert!(
+            matches!(res, Err(Error::Timeout { step: ConnectStep::Connect, timeout: t }) if t == timeout),
+            "{res:?}"
+        );
+
+        let res = Builder::new(format!("wss://{addr}/socket/websocket").parse().unwrap())
+            .unwrap()
+            .tls_timeout(timeout)
+            .connect()
+            .await;
+
+        assert!(
+            matches!(
+                res,
+                Err(Error::Timeout {
+                    step: ConnectStep::Tls,
+                    ..
+                })
+            ),
+            "{res:?}"
+        );
+
+        let res = Builder::new(format!("ws://{addr}/socket/websocket").parse().unwrap())
+            .unwrap()
+            .upgrade_timeout(timeout)
+            .connect()
+            .await;
+
+        assert!(
+            matches!(
+                res,
+                Err(Error::Timeout {
+                    step: ConnectStep::Upgrade,
+                    ..
+                })
+            ),
+            "{res:?}"
+        );
+
+        server.abort();
+    }
+
+    #[test]
+    fn append_params_to_query() {
+        let uri = Uri::from_static("wss://example.com/socket/websocket?vsn=2.0.0");
+
+        let params = serde_urlencoded::to_string([("token", "a b&c"), ("user", "42")]).unwrap();
+
+        assert_eq!(
+            append_query(&uri, &params).unwrap(),
+            "wss://example.com/socket/websocket?vsn=2.0.0&token=a+b%26c&user=42"
+        );
+
+        let uri = Uri::from_static("ws://localhost:4000/socket/websocket");
+
+        assert_eq!(append_query(&uri, "").unwrap(), uri);
+        assert_eq!(
+            append_query(&uri, "user=42").unwrap(),
+            "ws://localhost:4000/socket/websocket?user=42"
+        );
+    }
+
+    #[test]
+    fn check_selected_sub_protocol() {
+        let offered = ["v1.json".to_string()];
+
+        assert!(is_accepted(&offered, Some("v1.json"), false));
+        assert!(!is_accepted(&offered, Some("v2.json"), false));
+        assert!(!is_accepted(&offered, None, false));
+        assert!(is_accepted(&[], None, false));
 
-        trace!(status = %resp.status(), headers = ?resp.headers());
+        // The token is only used with the phoenix sub-protocol
+        let offered = ["v1.json".to_string(), PHOENIX_PROTOCOL.to_string()];
 
-        Ok(Client::new(connection, self.heartbeat))
+        assert!(is_accepted(&offered, Some("phoenix"), true));
+        assert!(!is_accepted(&offered, Some("v1.json"), true));
+        assert!(!is_accepted(
+            &offered,
+            Some("base64url.bearer.phx.dG9rZW4"),
+            true
+        ));
+        assert!(!is_accepted(&offered, None, true));
     }
 }
@@ -0,0 +1,508 @@
+//! Structured consumer of a channel, with the lifecycle driven by the client.
+//!
+//! Implement the [`ChannelHandler`] and pass it to [`Client::channel`] to join the topic, rejoin
+//! it when the channel crashes on the server and dispatch the messages to the handler hooks.
+
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, trace, warn};
+
+use crate::dispatch::{Filter, Subscription};
+use crate::message::{ControlEvent, Message, MessageKind, Reply};
+use crate::{Client, Error};
+
+/// Default time to wait for the reply to the join.
+///
+/// Same as the default `timeout` option of the phoenix.js `Socket`.
+pub(crate) const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default intervals to wait before rejoining a channel.
+///
+/// Same as the default `rejoinAfterMs` option of the phoenix.js `Socket`.
+const DEFAULT_REJOIN_AFTER: [Duration; 4] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Hooks called during the lifecycle of a [`Channel`].
+///
+/// The hooks returning a [`ControlFlow`] can [`Break`](ControlFlow::Break) to leave the channel.
+pub trait ChannelHandler: Send {
+    /// Payload of the messages received on the channel.
+    type Payload: DeserializeOwned + Send;
+
+    /// Called with the reply of the server when the channel is joined or rejoined.
+    fn on_join(
+        &mut self,
+        reply: Message<serde_json::Value>,
+    ) -> impl Future<Output = ControlFlow<()>> + Send;
+
+    /// Called for every message received on the channel.
+    fn on_message(
+        &mut self,
+        msg: Message<Self::Payload>,
+    ) -> impl Future<Output = ControlFlow<()>> + Send;
+
+    /// Called with the replies to the messages sent on the topic.
+    fn on_reply(
+        &mut self,
+        msg: Message<serde_json::Value>,
+    ) -> impl Future<Output = ControlFlow<()>> + Send {
+        let _ = msg;
+
+        async { ControlFlow::Continue(()) }
+    }
+
+    /// Called for the Phoenix control events other than the crash and close of the channel, like
+    /// `presence_state` and `presence_diff`.
+    fn on_control(
+        &mut self,
+        msg: Message<serde_json::Value>,
+    ) -> impl Future<Output = ControlFlow<()>> + Send {
+        let _ = msg;
+
+        async { ControlFlow::Continue(()) }
+    }
+
+    /// Called for a message with a payload that cannot be deserialized into the
+    /// [`Payload`](ChannelHandler::Payload).
+    ///
+    /// By default the message is logged and skipped.
+    fn on_invalid_payload(
+        &mut self,
+        msg: Message<serde_json::Value>,
+        error: serde_json::Error,
+    ) -> impl Future<Output = ControlFlow<()>> + Send {
+        async move {
+            warn!(message = msg.info(), %error, "invalid payload");
+
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Called when the join is rejected or the channel crashes on the server.
+    ///
+    /// The channel will be rejoined afterwards, also if the join times out without calling this
+    /// hook.
+    fn on_error(&mut self, msg: Message<serde_json::Value>) -> impl Future<Output = ()> + Send {
+        let _ = msg;
+
+        async {}
+    }
+
+    /// Called when the channel is closed by the server.
+    fn on_close(&mut self, msg: Message<serde_json::Value>) -> impl Future<Output = ()> + Send {
+        let _ = msg;
+
+        async {}
+    }
+
+    /// Called after the client left the channel.
+    fn on_leave(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Outcome of a join attempt.
+enum Join {
+    Joined,
+    /// Rejected or timed out.
+    Failed,
+    Left,
+}
+
+/// Channel joined and driven by the client.
+///
+/// The messages are received through a [`Subscription`], so a task must be receiving from the
+/// client, for example with [`Client::run`].
+#[derive(Debug)]
+pub struct Channel<'a, H, P> {
+    client: &'a Client,
+    topic: String,
+    params: P,
+    handler: H,
+    join_timeout: Duration,
+    rejoin_after: Vec<Duration>,
+}
+
+impl<'a, H, P> Channel<'a, H, P>
+where
+    H: ChannelHandler,
+    P: Serialize + Send + Sync,
+{
+    pub(crate) fn new(client: &'a Client, topic: &str, params: P, handler: H) -> Self {
+        Self {
+            client,
+            topic: topic.to_string(),
+            params,
+            handler,
+            join_timeout: DEFAULT_JOIN_TIMEOUT,
+            rejoin_after: DEFAULT_REJOIN_AFTER.to_vec(),
+        }
+    }
+
+    /// Set the time to wait for the reply to the join before trying again.
+    #[must_use]
+    pub fn join_timeout(mut self, timeout: Duration) -> Self {
+        self.join_timeout = timeout;
+
+        self
+    }
+
+    /// Set the intervals to wait before each attempt to rejoin the channel.
+    ///
+    /// The first interval is also waited before rejoining a crashed channel, and the last one is
+    /// used for all the next attempts.
+    #[must_use]
+    pub fn rejoin_after(mut self, rejoin_after: Vec<Duration>) -> Self {
+        self.rejoin_after = rejoin_after;
+
+        self
+    }
+
+    /// Joins the channel and dispatches the messages to the handler.
+    ///
+    /// Returns when the channel is left or closed, or when the client disconnects.
+    #[instrument(skip(self), fields(topic = self.topic))]
+    pub async fn run(mut self) -> Result<(), Error> {
+        let mut subscription = self.client.subscribe(Filter::topic(&self.topic));
+        let mut attempt = 0;
+
+        loop {
+            if attempt > 0 {
+                let wait = self
+                    .rejoin_after
+                    .get(attempt - 1)
+                    .or(self.rejoin_after.last())
+                    .copied()
+                    .unwrap_or_default();
+
+                debug!(attempt, ?wait, "rejoining channel");
+
+                tokio::time::sleep(wait).await;
+            }
+
+            attempt += 1;
+
+            let join_id = self
+                .client
+                .join_with_payload(&self.topic, &self.params)
+                .await?;
+
+            let join = tokio::time::timeout(
+                self.join_timeout,
+                self.wait_join(&mut subscription, join_id),
+            )
+            .await;
+
+            match join {
+                Ok(Ok(Join::Joined)) => {}
+                Ok(Ok(Join::Failed)) => continue,
+                Ok(Ok(Join::Left)) => return Ok(()),
+                Ok(Err(err)) => return Err(err),
+                Err(_elapsed) => {
+                    warn!(timeout = ?self.join_timeout, "join timed out");
+
+                    // Like phoenix.js, leave so the server doesn't keep a join the client gave up
+                    self.client.leave(&self.topic).await?;
+
+                    continue;
+                }
+            }
+
+            match self.dispatch(&mut subscription).await? {
+                // Wait the first interval before rejoining the crashed channel
+                ControlFlow::Continue(()) => attempt = 1,
+                ControlFlow::Break(()) => return Ok(()),
+            }
+        }
+    }
+
+    /// Waits for the reply to the join.
+    async fn wait_join(
+        &mut self,
+        subscription: &mut Subscription,
+        join_id: usize,
+    ) -> Result<Join, Error> {
+        let join_ref = join_id.to_string();
+
+        loop {
+            let msg = subscription.recv().await.ok_or(Error::Disconnected)?;
+
+            if msg.kind() != MessageKind::Reply || msg.message_reference.as_ref() != Some(&join_ref)
+            {
+                trace!(message = msg.info(), "skipping message before join");
+
+                continue;
+            }
+
+            let reply = Reply::<serde_json::Value>::deserialize(&msg.payload);
+
+            if !reply.as_ref().is_ok_and(Reply::is_ok) {
+                warn!(message = msg.info(), "join rejected");
+
+                self.handler.on_error(msg).await;
+
+                return Ok(Join::Failed);
+            }
+
+            debug!("channel joined");
+
+            if self.handler.on_join(msg).await.is_break() {
+                self.leave().await?;
+
+                return Ok(Join::Left);
+            }
+
+            return Ok(Join::Joined);
+        }
+    }
+
+    /// Dispatches the messages, returns [`Continue`](ControlFlow::Continue) to rejoin the channel.
+    async fn dispatch(
+        &mut self,
+        subscription: &mut Subscription,
+    ) -> Result<ControlFlow<()>, Error> {
+        while let Some(msg) = subscription.recv().await {
+            let flow = match msg.kind() {
+                MessageKind::Control(ControlEvent::Error) => {
+                    warn!(message = msg.info(), "channel crashed");
+
+                    self.handler.on_error(msg).await;
+
+                    return Ok(ControlFlow::Continue(()));
+                }
+                MessageKind::Control(ControlEvent::Close) => {
+                    debug!("channel closed");
+
+                    self.handler.on_close(msg).await;
+
+                    return Ok(ControlFlow::Break(()));
+                }
+                MessageKind::Reply => self.handler.on_reply(msg).await,
+                MessageKind::Control(_) => self.handler.on_control(msg).await,
+                MessageKind::Push | MessageKind::Broadcast => {
+                    match H::Payload::deserialize(&msg.payload) {
+                        Ok(payload) => self.handler.on_message(msg.with_payload(payload)).await,
+                        Err(err) => self.handler.on_invalid_payload(msg, err).await,
+                    }
+                }
+            };
+
+            if flow.is_break() {
+                self.leave().await?;
+
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+
+        Err(Error::Disconnected)
+    }
+
+    async fn leave(&mut self) -> Result<(), Error> {
+        self.client.leave(&self.topic).await?;
+
+        self.handler.on_leave().await;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::testing::{MockChannel, MockServer, MockSocket};
+
+    use super::*;
+
+    struct Room;
+
+    impl MockChannel for Room {
+        fn handle_in(
+            &mut self,
+            _socket: &mut MockSocket<'_>,
+            _event: &str,
+            _payload: serde_json::Value,
+        ) -> Option<Result<serde_json::Value, serde_json::Value>> {
+            Some(Ok(json!({})))
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct NewMsg {
+        body: String,
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Arc<Mutex<Vec<String>>>,
+        crashed: Option<Instant>,
+        rejoined_after: Arc<Mutex<Option<Duration>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, event: impl Into<String>) {
+            self.events.lock().unwrap().push(event.into());
+        }
+    }
+
+    impl ChannelHandler for Recorder {
+        type Payload = NewMsg;
+
+        async fn on_join(&mut self, _reply: Message<serde_json::Value>) -> ControlFlow<()> {
+            self.record("join");
+
+            match self.crashed {
+                Some(crashed) => {
+                    *self.rejoined_after.lock().unwrap() = Some(crashed.elapsed());
+
+                    ControlFlow::Break(())
+                }
+                None => ControlFlow::Continue(()),
+            }
+        }
+
+        async fn on_message(&mut self, msg: Message<NewMsg>) -> ControlFlow<()> {
+            self.record(format!("message {}", msg.payload.body));
+
+            ControlFlow::Continue(())
+        }
+
+        async fn on_reply(&mut self, _msg: Message<serde_json::Value>) -> ControlFlow<()> {
+            self.record("reply");
+
+            ControlFlow::Continue(())
+        }
+
+        async fn on_control(&mut self, msg: Message<serde_json::Value>) -> ControlFlow<()> {
+            self.record(format!("control {}", msg.event_name));
+
+            ControlFlow::Continue(())
+        }
+
+        async fn on_invalid_payload(
+            &mut self,
+            _msg: Message<serde_json::Value>,
+            _error: serde_json::Error,
+        ) -> ControlFlow<()> {
+            self.record("invalid");
+
+            ControlFlow::Continue(())
+        }
+
+        async fn on_error(&mut self, _msg: Message<serde_json::Value>) {
+            self.record("error");
+
+            self.crashed = Some(Instant::now());
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_and_rejoin_after_crash() {
+        let (client, server) = MockServer::new().channel("room:*", Room).connect().await;
+
+        let handler = Recorder::default();
+        let events = Arc::clone(&handler.events);
+        let rejoined_after = Arc::clone(&handler.rejoined_after);
+
+        let rejoin_after = Duration::from_millis(50);
+        let channel = client
+            .channel("room:1", (), handler)
+            .rejoin_after(vec![rejoin_after]);
+
+        let script = async {
+            while !server.is_joined("room:1") {
+                tokio::task::yield_now().await;
+            }
+
+            client.send("room:1", "ping", ()).await.unwrap();
+
+            server
+                .broadcast("room:1", "new_msg", json!({"body": "hi"}))
+                .unwrap();
+            server
+                .broadcast("room:1", "new_msg", json!({"text": "hi"}))
+                .unwrap();
+            server
+                .broadcast("room:1", "presence_diff", json!({}))
+                .unwrap();
+
+            assert!(server.crash("room:1"));
+        };
+
+        tokio::select! {
+            res = client.run() => panic!("client stopped: {res:?}"),
+            (res, ()) = async { tokio::join!(channel.run(), script) } => res.unwrap(),
+        }
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "join",
+                "reply",
+                "message hi",
+                "invalid",
+                "control presence_diff",
+                "error",
+                "join"
+            ]
+        );
+        assert!(rejoined_after.lock().unwrap().unwrap() >= rejoin_after);
+    }
+
+    #[tokio::test]
+    async fn leave_and_rejoin_after_join_timeout() {
+        let (stream, server) = tokio::io::duplex(4096);
+
+        // Receives the messages without ever replying
+        let server = tokio::spawn(async move {
+            let mut ws = async_tungstenite::tokio::accept_async(server)
+                .await
+                .unwrap();
+            let mut events = Vec::new();
+
+            while events.len() < 3 {
+                let frame = futures::StreamExt::next(&mut ws).await.unwrap().unwrap();
+                let msg: serde_json::Value =
+                    serde_json::from_str(frame.to_text().unwrap()).unwrap();
+
+                events.push(msg[3].as_str().unwrap().to_string());
+            }
+
+            // Keep the connection open until the test ends
+            (events, ws)
+        });
+
+        let client = crate::Builder::new(tungstenite::http::Uri::from_static(
+            "ws://localhost/socket/websocket",
+        ))
+        .unwrap()
+        .connect_with_stream(stream)
+        .await
+        .unwrap();
+
+        let channel = client
+            .channel("room:1", (), Recorder::default())
+            .join_timeout(Duration::from_millis(20))
+            .rejoin_after(vec![Duration::from_millis(10)]);
+
+        let (events, _ws) = tokio::select! {
+            res = client.run() => panic!("client stopped: {res:?}"),
+            res = channel.run() => panic!("channel stopped: {res:?}"),
+            events = server => events.unwrap(),
+        };
+
+        assert_eq!(events, ["phx_join", "phx_leave", "phx_join"]);
+    }
+}
@@ -5,28 +5,64 @@ use std::pin::pin;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 
-use async_tungstenite::tokio::ConnectStream;
-use async_tungstenite::{WebSocketReceiver, WebSocketSender, WebSocketStream};
-use futures::StreamExt;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
 use tokio::sync::Mutex;
-use tracing::{debug, instrument, trace};
-use tungstenite::http::Uri;
-
-use crate::message::{ChannelMsg, Message};
+use tracing::{debug, instrument, trace, warn};
+use tungstenite::handshake::client::Response;
+use tungstenite::http::{HeaderMap, StatusCode, Uri};
+
+use crate::channel::{Channel, ChannelHandler};
+use crate::dispatch::{Dispatcher, EventHandle, Filter, SUBSCRIPTION_CAPACITY, Subscription};
+use crate::event::IntoEvent;
+use crate::message::{Message, Push, RawFrame, RawMessage};
+use crate::transport::{SendError, Transport};
+use crate::typed::{ChannelSpec, TypedChannel};
 use crate::{Builder, Error, Map};
 
 /// Id to identify the response of a message sent by the client.
 pub type Id = usize;
 
-type Sender = WebSocketSender<ConnectStream>;
-type Receiver = WebSocketReceiver<ConnectStream>;
+/// Response of the server to the WebSocket upgrade.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    status: StatusCode,
+    headers: HeaderMap,
+}
+
+impl Handshake {
+    pub(crate) fn new(resp: Response) -> Self {
+        let (parts, _body) = resp.into_parts();
+
+        Self::from_parts(parts.status, parts.headers)
+    }
+
+    pub(crate) fn from_parts(status: StatusCode, headers: HeaderMap) -> Self {
+        Self { status, headers }
+    }
+
+    /// Returns the status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the headers of the response, like `set-cookie` or the server version.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns the sub-protocol selected by the server in the `Sec-WebSocket-Protocol` header.
+    pub fn protocol(&self) -> Option<&str> {
+        self.headers
+            .get(tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+    }
+}
 
 #[derive(Debug)]
 struct Reader {
     heartbeat: tokio::time::Interval,
-    receiver: Receiver,
 }
 
 /// Connection for the Phoenix channel
@@ -35,22 +71,31 @@ pub struct Client {
     join_id: AtomicUsize,
     msg_id: AtomicUsize,
     sent: AtomicBool,
-    writer: Mutex<Sender>,
+    /// Buffer reused to encode the text frames.
+    encode_buf: std::sync::Mutex<Vec<u8>>,
+    transport: Box<dyn Transport>,
+    /// Only one task receives the frames and sends the heartbeats.
     reader: Mutex<Reader>,
+    dispatcher: Dispatcher,
+    handshake: Handshake,
 }
 
 impl Client {
-    pub(crate) fn new(connection: WebSocketStream<ConnectStream>, heartbeat: Duration) -> Self {
-        let (writer, reader) = connection.split();
+    pub(crate) fn new<T>(transport: T, handshake: Handshake, heartbeat: Duration) -> Self
+    where
+        T: Transport + 'static,
+    {
         Self {
             join_id: AtomicUsize::new(1),
             msg_id: AtomicUsize::new(1),
             sent: AtomicBool::new(false),
-            writer: Mutex::new(writer),
+            encode_buf: std::sync::Mutex::default(),
+            transport: Box::new(transport),
             reader: Mutex::new(Reader {
                 heartbeat: tokio::time::interval(heartbeat),
-                receiver: reader,
             }),
+            dispatcher: Dispatcher::default(),
+            handshake,
         }
     }
 
@@ -63,12 +108,22 @@ impl Client {
         Builder::new(uri)
     }
 
+    /// Returns the response of the server to the WebSocket upgrade, or to the request opening the
+    /// long-poll session.
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
     /// Sets the join id.
     pub fn set_join_id(&self, join_id: usize) {
         self.join_id.store(join_id, Ordering::Release);
     }
 
     /// Joins a channel.
+    ///
+    /// The server replies with a `phx_reply` referencing the returned [`Id`], the status of the
+    /// reply is not checked by the client, call [`Message::into_reply`] on the received message to
+    /// get the response or the [`Error::ReplyError`].
     pub async fn join(&self, topic: &str) -> Result<Id, Error> {
         self.join_with_payload(topic, Map::default()).await
     }
@@ -82,7 +137,7 @@ impl Client {
         let join_id = self.join_id.load(Ordering::Acquire);
         let msg_id = self.next_id();
 
-        let msg = ChannelMsg::new(Some(join_id), Some(msg_id), topic, "phx_join", payload);
+        let msg = Push::new(Some(join_id), Some(msg_id), topic, "phx_join", payload);
 
         debug!(msg_id, "joining topic");
 
@@ -99,7 +154,7 @@ impl Client {
         let join_id = self.join_id.load(Ordering::Relaxed);
         let msg_id = self.next_id();
 
-        let msg = ChannelMsg::new(
+        let msg = Push::new(
             Some(join_id),
             Some(msg_id),
             topic,
@@ -117,6 +172,10 @@ impl Client {
     }
 
     /// Sends an event on a topic
+    ///
+    /// The server replies with a `phx_reply` referencing the returned [`Id`], the status of the
+    /// reply is not checked by the client, call [`Message::into_reply`] on the received message to
+    /// get the response or the [`Error::ReplyError`].
     #[instrument(skip(self, payload))]
     pub async fn send<P>(&self, topic: &str, event: &str, payload: P) -> Result<Id, Error>
     where
@@ -125,7 +184,7 @@ impl Client {
         let join_id = self.join_id.load(Ordering::Relaxed);
         let msg_id = self.next_id();
 
-        let msg = ChannelMsg::new(Some(join_id), Some(msg_id), topic, event, payload);
+        let msg = Push::new(Some(join_id), Some(msg_id), topic, event, payload);
 
         debug!(msg_id, "sending event");
 
@@ -136,25 +195,89 @@ impl Client {
         Ok(msg_id)
     }
 
+    /// Sends a typed event on a topic.
+    pub async fn send_event<E>(&self, topic: &str, event: E) -> Result<Id, Error>
+    where
+        E: IntoEvent,
+    {
+        let (event, payload) = event.into_event().map_err(Error::Serialize)?;
+
+        self.send(topic, event, payload).await
+    }
+
+    /// Sends an event on a topic with a payload already serialized to JSON.
+    ///
+    /// The payload is written in the message as is, without encoding it again.
+    #[instrument(skip(self, payload))]
+    pub async fn send_raw(
+        &self,
+        topic: &str,
+        event: &str,
+        payload: &RawValue,
+    ) -> Result<Id, Error> {
+        self.send(topic, event, payload).await
+    }
+
+    /// Sends an event on a topic with a binary payload.
+    ///
+    /// The message is sent as a binary WebSocket frame, with the Phoenix binary serializer format.
+    #[instrument(skip(self, payload))]
+    pub async fn send_binary(&self, topic: &str, event: &str, payload: &[u8]) -> Result<Id, Error> {
+        let join_id = self.join_id.load(Ordering::Relaxed);
+        let msg_id = self.next_id();
+
+        let msg = Push::new(Some(join_id), Some(msg_id), topic, event, payload);
+
+        debug!(msg_id, "sending binary event");
+
+        self.write_binary(msg).await?;
+
+        trace!(msg_id, "binary event sent");
+
+        Ok(msg_id)
+    }
+
     #[instrument(skip_all)]
-    async fn write_msg<P>(&self, msg: ChannelMsg<'_, P>) -> Result<(), Error>
+    async fn write_msg<P>(&self, msg: Push<'_, P>) -> Result<(), Error>
     where
         P: Serialize,
     {
-        let msg_json = serde_json::to_string(&msg).map_err(Error::Serialize)?;
+        let frame = {
+            // The buffer is still valid if a serialize implementation panicked
+            let mut buf = self
+                .encode_buf
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
 
-        trace!("writing on socket");
+            msg.encode_json(&mut buf)?
+        };
 
-        self.writer
-            .lock()
+        self.write_frame(tungstenite::Message::Text(frame), msg)
             .await
-            .send(tungstenite::Message::Text(msg_json.into()))
+    }
+
+    #[instrument(skip_all)]
+    async fn write_binary(&self, msg: Push<'_, &[u8]>) -> Result<(), Error> {
+        let frame = msg.encode_binary()?;
+
+        self.write_frame(tungstenite::Message::Binary(frame), msg)
             .await
-            .map_err(Box::new)
-            .map_err(|err| Error::Send {
+    }
+
+    async fn write_frame<P>(
+        &self,
+        frame: tungstenite::Message,
+        msg: Push<'_, P>,
+    ) -> Result<(), Error> {
+        trace!("writing on socket");
+
+        self.transport.send(frame).await.map_err(|err| match err {
+            SendError::WebSocket(err) => Error::Send {
                 msg: msg.into_err(),
                 backtrace: err,
-            })?;
+            },
+            SendError::Transport(err) => err,
+        })?;
 
         trace!("update sent flag");
 
@@ -163,44 +286,201 @@ impl Client {
         Ok(())
     }
 
+    /// Subscribes to the received messages matching the filter.
+    ///
+    /// Every message received by the client is forwarded to all the matching subscriptions, the
+    /// messages are only dispatched while a task is receiving from the client, for example with
+    /// [`Client::run`].
+    ///
+    /// The subscription buffers up to [`SUBSCRIPTION_CAPACITY`] messages, see
+    /// [`Client::subscribe_with_capacity`].
+    pub fn subscribe(&self, filter: Filter) -> Subscription {
+        self.subscribe_with_capacity(filter, SUBSCRIPTION_CAPACITY)
+    }
+
+    /// Subscribes to the received messages matching the filter, buffering up to `capacity`
+    /// messages.
+    ///
+    /// When the buffer is full the new messages are dropped for the subscription, see
+    /// [`Subscription`]. The capacity is at least one message.
+    pub fn subscribe_with_capacity(&self, filter: Filter, capacity: usize) -> Subscription {
+        self.dispatcher.subscribe(filter, capacity)
+    }
+
+    /// Registers a handler called for the messages with the topic and event.
+    ///
+    /// The handler is called by the task receiving the messages, like the subscriptions. It's
+    /// unregistered when the returned handle is dropped or with [`EventHandle::off`].
+    ///
+    /// The handler is called without holding any lock, so it can use the client. A message
+    /// dispatched while the handler is still running, for example one received because of the
+    /// handler itself, is skipped for this handler instead of calling it re-entrantly.
+    pub fn on<F>(&self, topic: &str, event: &str, handler: F) -> EventHandle
+    where
+        F: FnMut(Message<serde_json::Value>) + Send + 'static,
+    {
+        self.dispatcher
+            .on(Filter::topic(topic).event(event), handler)
+    }
+
+    /// Returns a channel on the topic driven by the handler.
+    ///
+    /// The channel is joined with the parameters when [`Channel::run`] is awaited.
+    pub fn channel<H, P>(&self, topic: &str, params: P, handler: H) -> Channel<'_, H, P>
+    where
+        H: ChannelHandler,
+        P: Serialize + Send + Sync,
+    {
+        Channel::new(self, topic, params, handler)
+    }
+
+    /// Joins the channel declared by the spec, waiting for the join reply.
+    ///
+    /// A task must be receiving from the client to receive the reply, for example with
+    /// [`Client::run`]. Returns [`Error::ReplyError`] if the join is rejected, or
+    /// [`Error::JoinTimeout`] if the server doesn't reply in the default timeout of
+    /// [`Channel::join_timeout`].
+    pub async fn join_channel<C>(
+        &self,
+        spec: C,
+        params: C::JoinParams,
+    ) -> Result<TypedChannel<'_, C>, Error>
+    where
+        C: ChannelSpec,
+    {
+        TypedChannel::join(self, spec, params).await
+    }
+
+    /// Receives and dispatches the messages to the subscriptions and handlers.
+    ///
+    /// Returns when the connection is closed or fails. The invalid frames are logged and skipped,
+    /// so they don't stop the delivery to the other subscribers.
+    #[instrument(skip(self))]
+    pub async fn run(&self) -> Result<(), Error> {
+        loop {
+            match self.recv::<serde::de::IgnoredAny>().await {
+                Ok(_) => {}
+                Err(Error::Disconnected) => return Ok(()),
+                Err(err) if err.is_invalid_frame() => {
+                    warn!(error = %err, "skipping invalid frame");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     /// Returns the next message in any channel.
+    ///
+    /// The message is also forwarded to the matching subscriptions. If the payload cannot be
+    /// deserialized into `P`, the message with the raw payload is returned in the
+    /// [`Error::Decode`].
     #[instrument(skip(self))]
     pub async fn recv<P>(&self) -> Result<Message<P>, Error>
     where
         P: DeserializeOwned,
     {
-        trace!("waiting for next message");
+        let frame = self.next_frame().await?;
 
-        let msg = self.next_msg().await?;
+        let msg = frame.message()?;
 
-        trace!(%msg, "WebSocket message received");
+        if !self.dispatcher.is_empty() {
+            self.dispatch(&msg);
+        }
 
-        msg.into_text()
-            .map_err(Box::new)
-            .map_err(Error::WebSocketMessageType)
-            .and_then(|txt| {
-                serde_json::from_str::<ChannelMsg<P>>(txt.as_str()).map_err(Error::Deserialize)
-            })
-            .map(|msg| {
-                let msg = Message::from(msg);
+        let payload = match msg.deserialize_payload::<P>() {
+            Ok(payload) => payload,
+            Err(err) => {
+                return Err(Error::Decode {
+                    msg: msg.to_message(),
+                    backtrace: err,
+                });
+            }
+        };
 
-                debug!(message = msg.info(), "message received");
+        debug!(message = msg.info(), kind = ?msg.kind(), "message received");
 
-                msg
-            })
+        Ok(msg.with_payload(payload))
     }
 
+    /// Returns the next frame in any channel, without deserializing the payload.
+    ///
+    /// The message can be parsed with [`RawFrame::message`], borrowing the topic and event from
+    /// the frame. The message is also forwarded to the matching subscriptions.
     #[instrument(skip(self))]
+    pub async fn recv_raw(&self) -> Result<RawFrame, Error> {
+        let frame = self.next_frame().await?;
+
+        if !self.dispatcher.is_empty() {
+            // The frame is returned even if invalid, the receiver can still inspect it
+            match frame.message() {
+                Ok(msg) => self.dispatch(&msg),
+                Err(err) => warn!(frame = frame.as_str(), error = %err, "couldn't dispatch frame"),
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Forwards the message to the subscribers, the message is still returned to the receiver if
+    /// it cannot be dispatched.
+    fn dispatch(&self, msg: &RawMessage<'_>) {
+        match msg.deserialize_payload() {
+            Ok(payload) => self.dispatcher.dispatch(&msg.with_payload(payload)),
+            Err(err) => warn!(message = msg.info(), error = %err, "couldn't dispatch message"),
+        }
+    }
+
+    async fn next_frame(&self) -> Result<RawFrame, Error> {
+        loop {
+            trace!("waiting for next message");
+
+            let msg = self.next_msg().await?;
+
+            trace!(%msg, "WebSocket message received");
+
+            match msg {
+                tungstenite::Message::Text(text) => return Ok(RawFrame::new(text)),
+                // The transport returns None after the close frame
+                tungstenite::Message::Close(frame) => debug!(?frame, "close frame received"),
+                tungstenite::Message::Ping(_)
+                | tungstenite::Message::Pong(_)
+                | tungstenite::Message::Frame(_) => trace!("skipping control frame"),
+                msg @ tungstenite::Message::Binary(_) => {
+                    return msg
+                        .into_text()
+                        .map(RawFrame::new)
+                        .map_err(Box::new)
+                        .map_err(Error::WebSocketMessageType);
+                }
+            }
+        }
+    }
+
+    /// Returns the next message from the transport.
+    ///
+    /// The dispatcher is closed when the connection fails, so the subscribers don't wait for
+    /// messages that will never be received.
     async fn next_msg(&self) -> Result<tungstenite::Message, Error> {
+        let res = self.recv_transport().await;
+
+        if res.is_err() {
+            self.dispatcher.close();
+        }
+
+        res
+    }
+
+    #[instrument(skip(self))]
+    async fn recv_transport(&self) -> Result<tungstenite::Message, Error> {
         trace!("waiting for reader lock");
         let mut reader = self.reader.lock().await;
         let reader = reader.deref_mut();
 
-        let mut receive = reader.receiver.next();
+        let mut receive = self.transport.recv();
 
         loop {
             trace!("waiting for next event or heartbeat");
-            match futures::future::select(pin!(reader.heartbeat.tick()), pin!(&mut receive)).await {
+            match futures::future::select(pin!(reader.heartbeat.tick()), &mut receive).await {
                 futures::future::Either::Left((_instant, _next)) => {
                     trace!("heartbeat interval");
                     self.check_and_send_heartbeat().await?;
@@ -213,12 +493,20 @@ impl Client {
                 futures::future::Either::Right((Some(res), _)) => {
                     trace!("next event");
 
-                    return res.map_err(Box::new).map_err(Error::Recv);
+                    return res;
                 }
             };
         }
     }
 
+    /// Closes the connection with the server.
+    #[instrument(skip(self))]
+    pub async fn close(&self) -> Result<(), Error> {
+        debug!("closing the connection");
+
+        self.transport.close().await
+    }
+
     #[instrument(skip(self))]
     async fn check_and_send_heartbeat(&self) -> Result<(), Error> {
         let val = self
@@ -236,8 +524,7 @@ impl Client {
 
                 let id = self.next_id();
 
-                let heartbeat =
-                    ChannelMsg::new(None, Some(id), "phoenix", "heartbeat", Map::default());
+                let heartbeat = Push::new(None, Some(id), "phoenix", "heartbeat", Map::default());
 
                 debug!(id, "sending heartbeat");
 
@@ -248,3 +535,64 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn run_skips_invalid_frames_and_closes_subscriptions() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            let mut ws = async_tungstenite::tokio::accept_async(server)
+                .await
+                .unwrap();
+
+            // Wait for the first heartbeat, so the client only writes the pong afterwards
+            futures::StreamExt::next(&mut ws).await.unwrap().unwrap();
+
+            ws.send(tungstenite::Message::Binary(vec![0xff].into()))
+                .await
+                .unwrap();
+            ws.send(tungstenite::Message::Text("not a message".into()))
+                .await
+                .unwrap();
+            ws.send(tungstenite::Message::Ping(Vec::new().into()))
+                .await
+                .unwrap();
+            ws.send(tungstenite::Message::Text(
+                r#"[null,null,"room:lobby","new_msg",{}]"#.into(),
+            ))
+            .await
+            .unwrap();
+
+            // Wait for the pong to the ping
+            let pong = futures::StreamExt::next(&mut ws).await.unwrap().unwrap();
+            assert!(pong.is_pong());
+
+            // Reset the connection without the closing handshake
+            drop(ws);
+        });
+
+        let client = Builder::new(Uri::from_static("ws://localhost/socket/websocket"))
+            .unwrap()
+            .connect_with_stream(client)
+            .await
+            .unwrap();
+
+        let mut subscription = client.subscribe(Filter::all());
+
+        let res = client.run().await;
+
+        server.await.unwrap();
+
+        assert!(matches!(res, Err(Error::Recv(_))), "{res:?}");
+
+        let msg = subscription.recv().await.unwrap();
+        assert_eq!(msg.event_name, "new_msg");
+        assert_eq!(subscription.recv().await, None);
+    }
+}
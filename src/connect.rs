@@ -0,0 +1,442 @@
+//! Establishes the connection step by step.
+//!
+//! The host is resolved, the TCP connection opened, the TLS session negotiated and the WebSocket
+//! upgraded separately, so each failure is returned as a distinct [`Error`].
+
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tokio::TokioAdapter;
+use futures::{AsyncRead, AsyncWrite};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, trace, warn};
+use tungstenite::client::uri_mode;
+use tungstenite::error::{ProtocolError, UrlError};
+use tungstenite::handshake::client::{Request, Response};
+use tungstenite::http::Uri;
+use tungstenite::protocol::{Role, WebSocketConfig};
+use tungstenite::stream::Mode;
+
+use crate::Error;
+use crate::error::ConnectStep;
+use crate::proxy::Proxy;
+
+/// Stream the WebSocket is upgraded on.
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl std::fmt::Debug for dyn Io {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Io").finish_non_exhaustive()
+    }
+}
+
+/// Type erased stream of the connection.
+pub(crate) type BoxIo = Box<dyn Io>;
+
+/// Proxy to connect through.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum ProxyConfig {
+    /// Connect directly to the server.
+    #[default]
+    None,
+    /// Connect through the proxy.
+    Proxy(Proxy),
+    /// Connect through the proxy configured in the environment.
+    Env,
+}
+
+/// Same as the default `longpollerTimeout` option of the phoenix.js `Socket`.
+const DEFAULT_LONG_POLL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Timeouts of the connection steps, [`None`] waits indefinitely.
+///
+/// The connect timeout includes the tunnel through the proxy.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    /// Resolve the host and open the connection, directly or to the proxy.
+    pub(crate) connect: Option<Duration>,
+    /// Establish the TLS session.
+    pub(crate) tls: Option<Duration>,
+    /// Upgrade the connection to a WebSocket.
+    pub(crate) upgrade: Option<Duration>,
+    /// Send a long-poll request and receive the response.
+    pub(crate) long_poll: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: None,
+            tls: None,
+            upgrade: None,
+            long_poll: Some(DEFAULT_LONG_POLL_TIMEOUT),
+        }
+    }
+}
+
+/// Opens the connections to the server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Dialer {
+    pub(crate) tls_config: Option<Arc<ClientConfig>>,
+    pub(crate) proxy: ProxyConfig,
+    #[cfg(unix)]
+    pub(crate) unix_socket: Option<PathBuf>,
+    pub(crate) timeouts: Timeouts,
+}
+
+impl Dialer {
+    /// Connects to the server and upgrades the connection to a WebSocket.
+    pub(crate) async fn connect(
+        &self,
+        request: Request,
+        ws_config: WebSocketConfig,
+    ) -> Result<(WebSocketStream<BoxIo>, Response), Error> {
+        let stream = self.dial(request.uri()).await?;
+
+        upgrade(request, stream, ws_config, self.timeouts.upgrade).await
+    }
+
+    /// Opens the connection to the server of the URI, through the proxy and TLS if configured.
+    pub(crate) async fn dial(&self, uri: &Uri) -> Result<BoxIo, Error> {
+        #[cfg(unix)]
+        if let Some(path) = &self.unix_socket {
+            let socket = with_timeout(ConnectStep::Connect, self.timeouts.connect, async {
+                tokio::net::UnixStream::connect(path)
+                    .await
+                    .map_err(Error::Io)
+            })
+            .await?;
+
+            debug!(?path, "Unix socket connected");
+
+            return Ok(Box::new(TokioAdapter::new(socket)));
+        }
+
+        let mode = uri_mode(uri).map_err(connect_err)?;
+        let host = host(uri)?;
+        let port = uri.port_u16().unwrap_or(match mode {
+            Mode::Plain => 80,
+            Mode::Tls => 443,
+        });
+
+        let proxy = match &self.proxy {
+            ProxyConfig::None => None,
+            ProxyConfig::Proxy(proxy) => Some(proxy.clone()),
+            ProxyConfig::Env => Proxy::from_env(matches!(mode, Mode::Tls), &host)?,
+        };
+
+        if let Some(proxy) = &proxy {
+            let socket = with_timeout(
+                ConnectStep::Connect,
+                self.timeouts.connect,
+                proxy.connect(&host, port),
+            )
+            .await?;
+
+            return self.secure(socket, mode, host).await;
+        }
+
+        let socket = with_timeout(ConnectStep::Connect, self.timeouts.connect, async {
+            let addrs = resolve(&host, port).await?;
+
+            trace!(?addrs, "host resolved");
+
+            TcpStream::connect(addrs.as_slice())
+                .await
+                .map_err(Error::Io)
+        })
+        .await?;
+
+        debug!(peer = ?socket.peer_addr().ok(), "TCP connected");
+
+        self.secure(socket, mode, host).await
+    }
+
+    /// Establishes the TLS session on the socket if required by the mode.
+    async fn secure<S>(&self, socket: S, mode: Mode, host: String) -> Result<BoxIo, Error>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        match mode {
+            Mode::Plain => Ok(Box::new(TokioAdapter::new(socket))),
+            Mode::Tls => {
+                let tls = tls(socket, host, self.tls_config.clone());
+
+                Ok(Box::new(TokioAdapter::new(
+                    with_timeout(ConnectStep::Tls, self.timeouts.tls, tls).await?,
+                )))
+            }
+        }
+    }
+}
+
+/// Fails with [`Error::Timeout`] if the step doesn't complete in time.
+pub(crate) async fn with_timeout<F, T>(
+    step: ConnectStep,
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| Error::Timeout { step, timeout })?
+}
+
+/// Returns the host of the URI, without the brackets of an IPv6 address.
+fn host(uri: &Uri) -> Result<String, Error> {
+    let host = uri
+        .host()
+        .ok_or_else(|| connect_err(tungstenite::Error::Url(UrlError::NoHostName)))?;
+
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+
+    Ok(host.to_string())
+}
+
+/// Resolves the host, failing if no address is found.
+pub(crate) async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| Error::Dns {
+            host: host.to_string(),
+            backtrace: err,
+        })?
+        .collect::<Vec<_>>();
+
+    if addrs.is_empty() {
+        return Err(Error::Dns {
+            host: host.to_string(),
+            backtrace: std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found"),
+        });
+    }
+
+    Ok(addrs)
+}
+
+async fn tls<S>(
+    socket: S,
+    host: String,
+    tls_config: Option<Arc<ClientConfig>>,
+) -> Result<tokio_rustls::client::TlsStream<S>, Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let tls_config = tls_config.unwrap_or_else(|| {
+        warn!("no TLS config provided, no certificate can be verified");
+
+        Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth(),
+        )
+    });
+
+    let domain = ServerName::try_from(host)
+        .map_err(|err| Error::Tls(std::io::Error::new(std::io::ErrorKind::InvalidInput, err)))?;
+
+    let stream = TlsConnector::from(tls_config)
+        .connect(domain, socket)
+        .await
+        .map_err(Error::Tls)?;
+
+    debug!("TLS session established");
+
+    Ok(stream)
+}
+
+/// Upgrades the connection on the stream to a WebSocket.
+///
+/// If the server selected a sub-protocol that wasn't offered, the [`Error::SubProtocol`] is
+/// returned with the selected one and without the offered, which are known by the caller.
+pub(crate) async fn upgrade(
+    request: Request,
+    stream: BoxIo,
+    ws_config: WebSocketConfig,
+    timeout: Option<Duration>,
+) -> Result<(WebSocketStream<BoxIo>, Response), Error> {
+    // The response is consumed by the handshake, a copy is needed to return the sub-protocol
+    let (stream, head) = RecordHead::new(stream);
+
+    let upgrade = async_tungstenite::client_async_with_config(request, stream, Some(ws_config));
+
+    with_timeout(ConnectStep::Upgrade, timeout, async {
+        let (ws, resp) = upgrade.await.map_err(|err| match err {
+            tungstenite::Error::Http(resp) => {
+                let (parts, body) = resp.into_parts();
+
+                Error::Rejected {
+                    status: parts.status,
+                    headers: Box::new(parts.headers),
+                    body,
+                }
+            }
+            tungstenite::Error::Protocol(ProtocolError::SecWebSocketSubProtocolError(_)) => {
+                Error::SubProtocol {
+                    offered: Vec::new(),
+                    selected: head.selected_protocol(),
+                }
+            }
+            err => connect_err(err),
+        })?;
+
+        // Only the head was read by the handshake, so the stream is unwrapped without losing the
+        // frames already received
+        let RecordHead { stream, rest, .. } = ws.into_inner();
+
+        let ws =
+            WebSocketStream::from_partially_read(stream, rest, Role::Client, Some(ws_config)).await;
+
+        Ok((ws, resp))
+    })
+    .await
+}
+
+/// Maximum size of the response head read by [`RecordHead`].
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Keeps a copy of the response head to the upgrade, while the handshake reads it.
+///
+/// The bytes after the end of the head are kept in the wrapper instead of being passed to the
+/// handshake, so the stream can be unwrapped after the upgrade.
+struct RecordHead {
+    stream: BoxIo,
+    head: Arc<std::sync::Mutex<Vec<u8>>>,
+    /// Bytes read after the end of the head.
+    rest: Vec<u8>,
+    read_head: bool,
+}
+
+impl RecordHead {
+    fn new(stream: BoxIo) -> (Self, ResponseHead) {
+        let head = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        (
+            Self {
+                stream,
+                head: Arc::clone(&head),
+                rest: Vec::new(),
+                read_head: false,
+            },
+            ResponseHead { head },
+        )
+    }
+}
+
+impl AsyncRead for RecordHead {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+
+        if !this.rest.is_empty() {
+            let len = buf.len().min(this.rest.len());
+
+            buf[..len].copy_from_slice(&this.rest[..len]);
+            this.rest.drain(..len);
+
+            return Poll::Ready(Ok(len));
+        }
+
+        let read = ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+
+        if this.read_head {
+            return Poll::Ready(Ok(read));
+        }
+
+        let mut head = this.head.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // The end of the head could be split between two reads
+        let prev = head.len();
+        let search = prev.saturating_sub(3);
+
+        head.extend_from_slice(&buf[..read]);
+
+        let Some(pos) = head[search..].windows(4).position(|w| w == b"\r\n\r\n") else {
+            if head.len() > MAX_HEAD_LEN {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the response head is too large",
+                )));
+            }
+
+            return Poll::Ready(Ok(read));
+        };
+
+        let end = search + pos + 4;
+        let len = end - prev;
+
+        this.rest.extend_from_slice(&buf[len..read]);
+        this.read_head = true;
+        head.truncate(end);
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for RecordHead {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// Response to the upgrade recorded by [`RecordHead`].
+struct ResponseHead {
+    head: Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl ResponseHead {
+    /// Returns the sub-protocol selected in the response.
+    fn selected_protocol(&self) -> Option<String> {
+        let head = self.head.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut resp = httparse::Response::new(&mut headers);
+
+        resp.parse(&head).ok()?;
+
+        resp.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("sec-websocket-protocol"))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .map(|value| value.trim().to_string())
+    }
+}
+
+fn connect_err(err: tungstenite::Error) -> Error {
+    Error::Connect(Box::new(err))
+}
@@ -0,0 +1,417 @@
+//! Fan-out of the received messages to multiple subscribers.
+//!
+//! Every message received by the [`Client`](crate::Client) is forwarded to all the
+//! [`Subscription`]s and event handlers with a matching [`Filter`]. The messages are dispatched by
+//! whichever task is receiving from the client, for example with
+//! [`Client::run`](crate::Client::run).
+
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{trace, warn};
+
+use crate::message::Message;
+
+/// Number of messages buffered by a [`Subscription`] created with
+/// [`Client::subscribe`](crate::Client::subscribe).
+pub const SUBSCRIPTION_CAPACITY: usize = 256;
+
+/// Filters the messages forwarded to a [`Subscription`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    topic: Option<TopicFilter>,
+    event: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TopicFilter {
+    Exact(String),
+    Prefix(String),
+}
+
+impl Filter {
+    /// Matches all the messages.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Matches the messages with the exact topic.
+    pub fn topic(topic: impl Into<String>) -> Self {
+        Self {
+            topic: Some(TopicFilter::Exact(topic.into())),
+            event: None,
+        }
+    }
+
+    /// Matches the messages with a topic starting with the prefix.
+    pub fn topic_prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            topic: Some(TopicFilter::Prefix(prefix.into())),
+            event: None,
+        }
+    }
+
+    /// Only matches the messages with the event name.
+    #[must_use]
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+
+        self
+    }
+
+    /// Returns true if the message matches the filter.
+    pub fn matches<P>(&self, msg: &Message<P>) -> bool {
+        let topic = match &self.topic {
+            Some(TopicFilter::Exact(topic)) => msg.topic_name == *topic,
+            Some(TopicFilter::Prefix(prefix)) => msg.topic_name.starts_with(prefix.as_str()),
+            None => true,
+        };
+
+        topic && self.event.as_ref().is_none_or(|e| msg.event_name == *e)
+    }
+}
+
+type Handler = Box<dyn FnMut(Message<serde_json::Value>) + Send>;
+
+/// The handler is taken out while it's called, so it's [`None`] if it's already running.
+type Callback = Arc<Mutex<Option<Handler>>>;
+
+/// Receiver of the messages matching a [`Filter`].
+///
+/// The messages are buffered until received. When the buffer is full, the new messages are
+/// dropped for this subscription only, so a slow subscriber doesn't block the client or the other
+/// subscribers. The dropped messages are counted by [`Subscription::dropped`].
+///
+/// The subscription is removed when dropped.
+#[derive(Debug)]
+pub struct Subscription {
+    rx: mpsc::Receiver<Message<serde_json::Value>>,
+    dropped: Arc<AtomicUsize>,
+    _registration: Registration,
+}
+
+impl Subscription {
+    /// Returns the next message matching the filter.
+    ///
+    /// Returns [`None`] if the [`Client`](crate::Client) was dropped or disconnected.
+    pub async fn recv(&mut self) -> Option<Message<serde_json::Value>> {
+        self.rx.recv().await
+    }
+
+    /// Returns the next message if one was already dispatched.
+    pub fn try_recv(&mut self) -> Option<Message<serde_json::Value>> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Returns the number of messages dropped because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Message<serde_json::Value>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Handle to an event handler registered with [`Client::on`](crate::Client::on).
+///
+/// The handler is unregistered with [`EventHandle::off`] or when the handle is dropped.
+#[derive(Debug)]
+#[must_use = "the handler is unregistered when the handle is dropped"]
+pub struct EventHandle {
+    _registration: Registration,
+}
+
+impl EventHandle {
+    /// Unregisters the event handler.
+    pub fn off(self) {}
+}
+
+/// Removes the subscriber when dropped.
+#[derive(Debug)]
+struct Registration {
+    id: usize,
+    subscribers: Weak<Mutex<Subscribers>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(subscribers) = self.subscribers.upgrade() {
+            lock(&subscribers).remove(self.id);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    next_id: usize,
+    entries: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    fn remove(&mut self, id: usize) {
+        self.entries.retain(|s| s.id != id);
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: usize,
+    filter: Filter,
+    sink: Sink,
+}
+
+enum Sink {
+    Channel {
+        tx: mpsc::Sender<Message<serde_json::Value>>,
+        dropped: Arc<AtomicUsize>,
+    },
+    Callback(Callback),
+}
+
+impl Debug for Sink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Channel { tx, dropped } => f
+                .debug_struct("Channel")
+                .field("tx", tx)
+                .field("dropped", dropped)
+                .finish(),
+            Self::Callback(_) => f.debug_tuple("Callback").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Forwards the received messages to the subscribers.
+#[derive(Debug, Default)]
+pub(crate) struct Dispatcher {
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Dispatcher {
+    /// Subscribes with a buffer of the capacity, at least one message.
+    pub(crate) fn subscribe(&self, filter: Filter, capacity: usize) -> Subscription {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        Subscription {
+            rx,
+            dropped: Arc::clone(&dropped),
+            _registration: self.register(filter, Sink::Channel { tx, dropped }),
+        }
+    }
+
+    pub(crate) fn on<F>(&self, filter: Filter, handler: F) -> EventHandle
+    where
+        F: FnMut(Message<serde_json::Value>) + Send + 'static,
+    {
+        EventHandle {
+            _registration: self.register(
+                filter,
+                Sink::Callback(Arc::new(Mutex::new(Some(Box::new(handler))))),
+            ),
+        }
+    }
+
+    fn register(&self, filter: Filter, sink: Sink) -> Registration {
+        let mut subscribers = lock(&self.subscribers);
+
+        let id = subscribers.next_id;
+        subscribers.next_id = subscribers.next_id.wrapping_add(1);
+
+        subscribers.entries.push(Subscriber { id, filter, sink });
+
+        Registration {
+            id,
+            subscribers: Arc::downgrade(&self.subscribers),
+        }
+    }
+
+    /// Removes all the subscribers, closing the subscriptions.
+    pub(crate) fn close(&self) {
+        lock(&self.subscribers).entries.clear();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        lock(&self.subscribers).entries.is_empty()
+    }
+
+    pub(crate) fn dispatch(&self, msg: &Message<serde_json::Value>) {
+        let mut callbacks = Vec::new();
+
+        {
+            let subscribers = lock(&self.subscribers);
+
+            for subscriber in subscribers.entries.iter().filter(|s| s.filter.matches(msg)) {
+                trace!(id = subscriber.id, "dispatching message");
+
+                match &subscriber.sink {
+                    Sink::Channel { tx, dropped } => match tx.try_send(msg.clone()) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            warn!(id = subscriber.id, "subscription full, message dropped");
+
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // The subscription removes itself when dropped
+                        Err(TrySendError::Closed(_)) => {}
+                    },
+                    Sink::Callback(callback) => callbacks.push(Arc::clone(callback)),
+                }
+            }
+        }
+
+        // Called without holding any lock, so a handler can register or unregister handlers and
+        // dispatch messages itself
+        for callback in callbacks {
+            let Some(mut handler) = lock(&callback).take() else {
+                warn!("event handler already running, message skipped");
+
+                continue;
+            };
+
+            (handler)(msg.clone());
+
+            *lock(&callback) = Some(handler);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T>
+where
+    T: ?Sized,
+{
+    // The state is still valid even if a handler panicked
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn message(topic: &str, event: &str) -> Message<serde_json::Value> {
+        Message {
+            join_reference: None,
+            message_reference: None,
+            topic_name: topic.to_string(),
+            event_name: event.to_string(),
+            payload: serde_json::Value::Null,
+        }
+    }
+
+    #[test]
+    fn filter_matches() {
+        let msg = message("room:42", "new_msg");
+
+        assert!(Filter::all().matches(&msg));
+        assert!(Filter::topic("room:42").matches(&msg));
+        assert!(!Filter::topic("room:4").matches(&msg));
+        assert!(Filter::topic_prefix("room:").matches(&msg));
+        assert!(!Filter::topic_prefix("user:").matches(&msg));
+        assert!(Filter::topic_prefix("room:").event("new_msg").matches(&msg));
+        assert!(!Filter::topic("room:42").event("phx_reply").matches(&msg));
+    }
+
+    #[test]
+    fn dispatch_to_all_subscribers() {
+        let dispatcher = Dispatcher::default();
+
+        let mut all = dispatcher.subscribe(Filter::all(), SUBSCRIPTION_CAPACITY);
+        let mut room = dispatcher.subscribe(Filter::topic("room:42"), SUBSCRIPTION_CAPACITY);
+        let mut user = dispatcher.subscribe(Filter::topic_prefix("user:"), SUBSCRIPTION_CAPACITY);
+
+        let msg = message("room:42", "new_msg");
+        dispatcher.dispatch(&msg);
+
+        assert_eq!(all.try_recv(), Some(msg.clone()));
+        assert_eq!(room.try_recv(), Some(msg));
+        assert_eq!(user.try_recv(), None);
+
+        drop(all);
+        drop(room);
+        drop(user);
+
+        assert!(dispatcher.is_empty());
+    }
+
+    #[test]
+    fn drop_messages_of_full_subscription() {
+        let dispatcher = Dispatcher::default();
+
+        let mut slow = dispatcher.subscribe(Filter::all(), 1);
+        let mut fast = dispatcher.subscribe(Filter::all(), 2);
+
+        let first = message("room:42", "first");
+        let second = message("room:42", "second");
+        dispatcher.dispatch(&first);
+        dispatcher.dispatch(&second);
+
+        assert_eq!(slow.dropped(), 1);
+        assert_eq!(slow.try_recv(), Some(first.clone()));
+        assert_eq!(slow.try_recv(), None);
+
+        assert_eq!(fast.dropped(), 0);
+        assert_eq!(fast.try_recv(), Some(first));
+        assert_eq!(fast.try_recv(), Some(second));
+    }
+
+    #[test]
+    fn call_event_handlers() {
+        let dispatcher = Dispatcher::default();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let handler_received = Arc::clone(&received);
+        let handle = dispatcher.on(Filter::topic("room:42").event("new_msg"), move |msg| {
+            handler_received.lock().unwrap().push(msg)
+        });
+
+        let msg = message("room:42", "new_msg");
+        dispatcher.dispatch(&msg);
+        dispatcher.dispatch(&message("room:42", "phx_reply"));
+
+        handle.off();
+
+        dispatcher.dispatch(&msg);
+
+        assert_eq!(*received.lock().unwrap(), [msg]);
+        assert!(dispatcher.is_empty());
+    }
+
+    #[test]
+    fn skip_reentrant_event_handler() {
+        let dispatcher = Arc::new(Dispatcher::default());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let handler_dispatcher = Arc::downgrade(&dispatcher);
+        let handler_received = Arc::clone(&received);
+        let _handle = dispatcher.on(Filter::all(), move |msg| {
+            handler_received.lock().unwrap().push(msg);
+
+            if let Some(dispatcher) = handler_dispatcher.upgrade() {
+                dispatcher.dispatch(&message("room:42", "loop"));
+            }
+        });
+
+        let msg = message("room:42", "new_msg");
+        dispatcher.dispatch(&msg);
+        dispatcher.dispatch(&msg);
+
+        assert_eq!(*received.lock().unwrap(), [msg.clone(), msg]);
+    }
+}
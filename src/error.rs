@@ -1,13 +1,18 @@
 //! Errors returned by the client.
 
+use serde_json::value::RawValue;
 use tungstenite::http;
 
-use crate::message::Message;
+use crate::message::{ControlEvent, Message};
 
 type TungsteniteError = Box<tungstenite::Error>;
 
+/// Error returned by an authentication token provider.
+pub type TokenError = Box<dyn std::error::Error + Send + Sync>;
+
 /// Error returned by the [`Client`](crate::client::Client) or connection.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     /// Couldn't add headers to uri.
     #[error("couldn't add the vsn header to uri")]
@@ -18,12 +23,98 @@ pub enum Error {
     /// Couldn't connect to the web-socket
     #[error("couldn't connect to the web-socket")]
     Connect(#[source] TungsteniteError),
+    /// Couldn't resolve the host of the uri.
+    #[error("couldn't resolve the host {host}")]
+    Dns {
+        /// Host of the uri
+        host: String,
+        #[source]
+        /// Backtrace error
+        backtrace: std::io::Error,
+    },
+    /// Couldn't open the TCP connection to the server.
+    #[error("couldn't open the connection to the server")]
+    Io(#[source] std::io::Error),
+    /// Invalid proxy uri.
+    #[error("invalid proxy uri {0}")]
+    ProxyUri(String),
+    /// Couldn't tunnel the connection through the proxy.
+    #[error("couldn't tunnel the connection through the proxy")]
+    Proxy(#[source] std::io::Error),
+    /// The proxy refused to tunnel the connection.
+    #[error("the proxy refused to tunnel the connection with status {status}")]
+    ProxyRejected {
+        /// Status of the response of the proxy
+        status: http::StatusCode,
+    },
+    /// The SOCKS5 proxy refused to connect to the server.
+    #[error("the SOCKS5 proxy refused to connect with reply code {code}")]
+    SocksRejected {
+        /// Reply code of the proxy
+        code: u8,
+    },
+    /// Couldn't establish the TLS session.
+    #[error("couldn't establish the TLS session")]
+    Tls(#[source] std::io::Error),
+    /// A step of the connection didn't complete in time.
+    #[error("the {step} didn't complete in {timeout:?}")]
+    Timeout {
+        /// Step of the connection that timed out
+        step: ConnectStep,
+        /// Configured timeout of the step
+        timeout: std::time::Duration,
+    },
+    /// The server rejected the WebSocket upgrade.
+    #[error("the server rejected the web-socket upgrade with status {status}")]
+    Rejected {
+        /// Status of the HTTP response
+        status: http::StatusCode,
+        /// Headers of the HTTP response
+        headers: Box<http::HeaderMap>,
+        /// Body of the HTTP response, if any
+        body: Option<Vec<u8>>,
+    },
+    /// The server didn't select one of the offered sub-protocols.
+    #[error("the server didn't select one of the offered sub-protocols {offered:?}")]
+    SubProtocol {
+        /// Sub-protocols offered to the server, without the authentication token
+        offered: Vec<String>,
+        /// Sub-protocol selected by the server, if any
+        selected: Option<String>,
+    },
+    /// The authentication token provider failed.
+    #[error("couldn't get the authentication token")]
+    AuthToken(#[source] TokenError),
+    /// Couldn't encode the connect params in the query of the uri.
+    #[error("couldn't encode the connect params")]
+    Params(#[source] serde_urlencoded::ser::Error),
+    /// Couldn't send the request to the long-poll endpoint or read the response.
+    #[error("couldn't send the long-poll request")]
+    LongPoll(#[source] std::io::Error),
+    /// Binary messages cannot be sent with the long-poll transport.
+    #[error("binary messages are not supported by the long-poll transport")]
+    LongPollBinary,
     /// Couldn't serialize message
     #[error("couldn't serialize message")]
     Serialize(#[source] serde_json::Error),
     /// Couldn't de-serialize message
     #[error("couldn't deserialize message")]
     Deserialize(#[source] serde_json::Error),
+    /// Couldn't decode the payload of a received message
+    #[error("couldn't decode the payload of the message {msg}")]
+    Decode {
+        /// The received message with the raw payload
+        msg: Message<Box<RawValue>>,
+        #[source]
+        /// Backtrace error
+        backtrace: serde_json::Error,
+    },
+    /// Received an event not known by the typed event
+    #[error("unknown event {0}")]
+    UnknownEvent(String),
+    /// Field too long to be encoded in a binary message
+    #[error("the {0} is longer than 255 bytes and cannot be encoded in a binary message")]
+    BinaryFieldLength(&'static str),
     /// Couldn't send a message
     #[error("couldn't send message {msg}")]
     Send {
@@ -36,10 +127,163 @@ pub enum Error {
     /// Couldn't receive the message
     #[error("couldn't receive the message")]
     Recv(#[source] TungsteniteError),
+    /// Couldn't close the connection
+    #[error("couldn't close the connection")]
+    Close(#[source] TungsteniteError),
     /// Couldn't decode WebSocket message, not of type text
     #[error("couldn't decode websocket message, not of type text")]
     WebSocketMessageType(#[source] TungsteniteError),
+    /// The server replied with an error status
+    #[error("the server replied with an error to the message {msg}")]
+    ReplyError {
+        /// The reply message
+        msg: Message<()>,
+        /// Response of the error reply
+        response: Box<serde_json::Value>,
+    },
+    /// Expected a reply, but received a message with a different event
+    #[error("the message {0} is not a phx_reply")]
+    NotReply(Message<()>),
+    /// The channel crashed or was closed by the server
+    #[error("the channel {topic} was closed by the server with {event}")]
+    ChannelClosed {
+        /// Topic of the channel
+        topic: String,
+        /// The `phx_error` or `phx_close` event
+        event: ControlEvent,
+    },
+    /// The server didn't reply to the join of the channel in time
+    #[error("the join of the channel {topic} didn't complete in {timeout:?}")]
+    JoinTimeout {
+        /// Topic of the channel
+        topic: String,
+        /// Timeout of the join
+        timeout: std::time::Duration,
+    },
     /// Disconnected from the web socket
     #[error("the web-socket disconnected")]
     Disconnected,
 }
+
+/// Step of the connection to the server, see [`Error::Timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ConnectStep {
+    /// Resolving the host and opening the connection, including the tunnel through the proxy.
+    Connect,
+    /// Establishing the TLS session.
+    Tls,
+    /// Upgrading the connection to a WebSocket.
+    Upgrade,
+    /// Sending a long-poll request and receiving the response, including the time the server
+    /// holds the poll.
+    LongPoll,
+}
+
+impl std::fmt::Display for ConnectStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectStep::Connect => write!(f, "connection to the server"),
+            ConnectStep::Tls => write!(f, "TLS handshake"),
+            ConnectStep::Upgrade => write!(f, "web-socket upgrade"),
+            ConnectStep::LongPoll => write!(f, "long-poll request"),
+        }
+    }
+}
+
+impl Error {
+    /// Returns true if the server rejected the WebSocket upgrade with `401 Unauthorized` or
+    /// `403 Forbidden`.
+    pub fn is_auth_rejected(&self) -> bool {
+        matches!(
+            self,
+            Error::Rejected {
+                status: http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN,
+                ..
+            }
+        )
+    }
+
+    /// Returns true if a single received frame is invalid, while the connection is still usable.
+    pub(crate) fn is_invalid_frame(&self) -> bool {
+        matches!(
+            self,
+            Error::WebSocketMessageType(_) | Error::Deserialize(_) | Error::Decode { .. }
+        )
+    }
+
+    /// Returns true if the error is transient and the operation can be retried, for example by
+    /// connecting again.
+    ///
+    /// The errors caused by the configuration, the messages or a rejected authentication are not
+    /// retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Dns { .. }
+            | Error::Io(_)
+            | Error::Proxy(_)
+            | Error::Timeout { .. }
+            | Error::JoinTimeout { .. }
+            | Error::Recv(_)
+            | Error::Disconnected => true,
+            Error::Tls(err) | Error::LongPoll(err) => {
+                err.kind() != std::io::ErrorKind::InvalidData
+                    && err.kind() != std::io::ErrorKind::InvalidInput
+            }
+            // General failure, network or host unreachable, connection refused and TTL expired
+            Error::SocksRejected { code } => matches!(code, 1 | 3..=6),
+            Error::Rejected { status, .. } | Error::ProxyRejected { status } => {
+                status.is_server_error()
+                    || *status == http::StatusCode::REQUEST_TIMEOUT
+                    || *status == http::StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Connect(err) | Error::Send { backtrace: err, .. } | Error::Close(err) => {
+                matches!(
+                    err.as_ref(),
+                    tungstenite::Error::Io(_)
+                        | tungstenite::Error::ConnectionClosed
+                        | tungstenite::Error::AlreadyClosed
+                )
+            }
+            Error::Uri(_)
+            | Error::UriBuild(_)
+            | Error::SubProtocol { .. }
+            | Error::ProxyUri(_)
+            | Error::AuthToken(_)
+            | Error::Params(_)
+            | Error::LongPollBinary
+            | Error::Serialize(_)
+            | Error::Deserialize(_)
+            | Error::Decode { .. }
+            | Error::UnknownEvent(_)
+            | Error::BinaryFieldLength(_)
+            | Error::WebSocketMessageType(_)
+            | Error::ReplyError { .. }
+            | Error::NotReply(_)
+            | Error::ChannelClosed { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(status: http::StatusCode) -> Error {
+        Error::Rejected {
+            status,
+            headers: Box::default(),
+            body: None,
+        }
+    }
+
+    #[test]
+    fn classify_rejected_upgrade() {
+        assert!(rejected(http::StatusCode::FORBIDDEN).is_auth_rejected());
+        assert!(!rejected(http::StatusCode::FORBIDDEN).is_retryable());
+        assert!(rejected(http::StatusCode::SERVICE_UNAVAILABLE).is_retryable());
+        assert!(!rejected(http::StatusCode::NOT_FOUND).is_retryable());
+        assert!(!Error::Disconnected.is_auth_rejected());
+        assert!(Error::Disconnected.is_retryable());
+    }
+}
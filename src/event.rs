@@ -0,0 +1,161 @@
+//! Typed events of a channel.
+//!
+//! The events of a channel can be modeled as an enum implementing [`IntoEvent`] to send them with
+//! [`Client::send_event`](crate::Client::send_event), and [`FromEvent`] to decode them with
+//! [`Message::into_event`]. With the `derive` feature both traits can be derived with
+//! [`PhoenixEvent`](crate::PhoenixEvent).
+
+use crate::Error;
+use crate::message::Message;
+
+/// Converts a value into the event name and payload of a message.
+pub trait IntoEvent {
+    /// Returns the event name and the payload to send.
+    fn into_event(self) -> Result<(&'static str, serde_json::Value), serde_json::Error>;
+}
+
+/// Decodes a value from the event name and payload of a message.
+pub trait FromEvent: Sized {
+    /// Decodes the event, returns [`Error::UnknownEvent`] if the event name is not known.
+    fn from_event(event: &str, payload: serde_json::Value) -> Result<Self, Error>;
+}
+
+impl Message<serde_json::Value> {
+    /// Decodes the event name and payload in a typed event.
+    pub fn into_event<E>(self) -> Result<Message<E>, Error>
+    where
+        E: FromEvent,
+    {
+        let payload = E::from_event(&self.event_name, self.payload)?;
+
+        Ok(Message {
+            join_reference: self.join_reference,
+            message_reference: self.message_reference,
+            topic_name: self.topic_name,
+            event_name: self.event_name,
+            payload,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::PhoenixEvent;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Body {
+        body: String,
+    }
+
+    #[derive(Debug, PartialEq, PhoenixEvent)]
+    enum RoomEvent {
+        #[event("new_msg")]
+        NewMsg(Body),
+        Typing {
+            user: String,
+            active: Option<bool>,
+        },
+        Ping,
+    }
+
+    fn message(event: &str, payload: serde_json::Value) -> Message<serde_json::Value> {
+        Message {
+            join_reference: None,
+            message_reference: None,
+            topic_name: "room:lobby".to_string(),
+            event_name: event.to_string(),
+            payload,
+        }
+    }
+
+    #[test]
+    fn derive_into_event() {
+        let new_msg = RoomEvent::NewMsg(Body {
+            body: "hello".to_string(),
+        });
+
+        assert_eq!(
+            new_msg.into_event().unwrap(),
+            ("new_msg", serde_json::json!({"body": "hello"}))
+        );
+
+        let typing = RoomEvent::Typing {
+            user: "ada".to_string(),
+            active: Some(true),
+        };
+
+        assert_eq!(
+            typing.into_event().unwrap(),
+            ("typing", serde_json::json!({"user": "ada", "active": true}))
+        );
+
+        assert_eq!(
+            RoomEvent::Ping.into_event().unwrap(),
+            ("ping", serde_json::json!({}))
+        );
+    }
+
+    #[test]
+    fn derive_from_event() {
+        let msg = message("new_msg", serde_json::json!({"body": "hello"}))
+            .into_event::<RoomEvent>()
+            .unwrap();
+
+        assert_eq!(msg.topic_name, "room:lobby");
+        assert_eq!(
+            msg.payload,
+            RoomEvent::NewMsg(Body {
+                body: "hello".to_string()
+            })
+        );
+
+        let typing = message("typing", serde_json::json!({"user": "ada"}))
+            .into_event::<RoomEvent>()
+            .unwrap();
+
+        assert_eq!(
+            typing.payload,
+            RoomEvent::Typing {
+                user: "ada".to_string(),
+                active: None
+            }
+        );
+
+        let unknown = message("phx_reply", serde_json::json!({})).into_event::<RoomEvent>();
+
+        assert!(matches!(unknown, Err(Error::UnknownEvent(event)) if event == "phx_reply"));
+
+        let missing =
+            message("typing", serde_json::json!({"active": true})).into_event::<RoomEvent>();
+
+        assert!(
+            matches!(&missing, Err(Error::Deserialize(err)) if err.to_string() == "missing field `user`"),
+            "{missing:?}"
+        );
+    }
+
+    #[derive(Debug, PartialEq, PhoenixEvent)]
+    enum GenericEvent<T> {
+        Update { value: T },
+    }
+
+    #[test]
+    fn derive_generic_event() {
+        let update = GenericEvent::Update { value: 42u32 };
+
+        assert_eq!(
+            update.into_event().unwrap(),
+            ("update", serde_json::json!({"value": 42}))
+        );
+
+        let msg = message("update", serde_json::json!({"value": 42}))
+            .into_event::<GenericEvent<u32>>()
+            .unwrap();
+
+        assert_eq!(msg.payload, GenericEvent::Update { value: 42 });
+    }
+}
@@ -8,9 +8,20 @@
 )]
 
 pub mod builder;
+pub mod channel;
 pub mod client;
+mod connect;
+pub mod dispatch;
 pub mod error;
+pub mod event;
+mod longpoll;
 pub mod message;
+pub mod proxy;
+pub mod router;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod transport;
+pub mod typed;
 
 /// Payload sent as last argument of a [`Message`].
 pub type Map = rustc_hash::FxHashMap<String, String>;
@@ -20,6 +31,91 @@ pub use self::client::Client;
 pub use self::error::Error;
 pub use self::message::Message;
 
+#[cfg(feature = "derive")]
+pub use phoenix_chan_derive::PhoenixEvent;
+
+/// Items used by the code generated from the derive macros, not part of the public API.
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub mod __private {
+    use serde::de::{DeserializeOwned, Deserializer, Error as _, Visitor};
+
+    pub use serde;
+
+    /// Deserializes a field missing from the payload, only an [`Option`] can be absent.
+    pub fn missing_field<T>(field: &'static str) -> Result<T, serde_json::Error>
+    where
+        T: DeserializeOwned,
+    {
+        T::deserialize(MissingField(field))
+    }
+
+    struct MissingField(&'static str);
+
+    impl<'de> Deserializer<'de> for MissingField {
+        type Error = serde_json::Error;
+
+        fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            Err(serde_json::Error::missing_field(self.0))
+        }
+
+        fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_none()
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+            ignored_any
+        }
+    }
+}
+
+// Makes the derive macros usable inside the crate tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as phoenix_chan;
+
+/// Internal functions exposed for the benchmarks, not part of the public API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod __bench {
+    use bytes::Bytes;
+    use serde::Serialize;
+    use tungstenite::Utf8Bytes;
+
+    use crate::Error;
+    use crate::client::Id;
+    use crate::message::Push;
+
+    pub fn encode_json<P>(
+        buf: &mut Vec<u8>,
+        msg_id: Id,
+        topic: &str,
+        event: &str,
+        payload: P,
+    ) -> Result<Utf8Bytes, Error>
+    where
+        P: Serialize,
+    {
+        Push::new(Some(1), Some(msg_id), topic, event, payload).encode_json(buf)
+    }
+
+    pub fn encode_binary(
+        msg_id: Id,
+        topic: &str,
+        event: &str,
+        payload: &[u8],
+    ) -> Result<Bytes, Error> {
+        Push::new(Some(1), Some(msg_id), topic, event, payload).encode_binary()
+    }
+}
+
 // pub dependencies
 pub use rustls;
 pub use serde_json;
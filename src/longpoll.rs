@@ -0,0 +1,1194 @@
+//! Long-poll transport, used when the WebSocket can't be upgraded.
+//!
+//! Same protocol of the `LongPoll` transport of phoenix.js: the messages are received by polling
+//! the `longpoll` endpoint with a `GET` request, and sent in batches of newline delimited JSON
+//! with a `POST` request. The connections are kept alive between the requests, and the session is
+//! identified by the token returned by the server.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tracing::{debug, instrument, trace};
+use tungstenite::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri, header};
+
+use crate::Error;
+use crate::builder::append_query;
+use crate::client::Handshake;
+use crate::connect::{BoxIo, Dialer, with_timeout};
+use crate::error::ConnectStep;
+use crate::transport::{SendError, Transport};
+
+/// Header with the authentication token, see the `auth_token` option of the Phoenix socket.
+const AUTH_TOKEN_HEADER: &str = "x-phoenix-authtoken";
+
+/// Maximum number of headers parsed in a response.
+const MAX_HEADERS: usize = 64;
+
+/// Maximum size of the head of a response.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Maximum size of the body of a response, the default maximum size of a WebSocket frame.
+const MAX_BODY_LEN: usize = 16 << 20;
+
+/// Maximum number of idle connections kept alive, one for the polls and one for the batches.
+const MAX_IDLE: usize = 2;
+
+/// Body of the responses of the long-poll endpoint.
+///
+/// The HTTP status is always `200 OK`, the status of the request is in the body.
+#[derive(Debug, Deserialize)]
+struct PollResponse {
+    status: u16,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    messages: Vec<String>,
+}
+
+/// Response to an HTTP request.
+struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Head of an HTTP response.
+struct Head {
+    len: usize,
+    status: StatusCode,
+    headers: HeaderMap,
+    keep_alive: bool,
+}
+
+/// Failure of a request on a connection.
+enum Failure {
+    /// The connection was closed before the response, the request can be sent on a new one.
+    Closed(std::io::Error),
+    Error(Error),
+}
+
+/// Outcome of a batch, shared by the tasks that queued a message in it.
+type BatchResult = Result<(), Arc<Error>>;
+
+/// Message waiting to be sent in the next batch.
+#[derive(Debug)]
+struct Queued {
+    id: u64,
+    text: String,
+    tx: oneshot::Sender<BatchResult>,
+}
+
+/// Removes the message from the batch if the task is cancelled before the message is sent.
+struct Dequeue<'a> {
+    batch: &'a Mutex<Vec<Queued>>,
+    id: u64,
+}
+
+impl Drop for Dequeue<'_> {
+    fn drop(&mut self) {
+        lock(self.batch).retain(|queued| queued.id != self.id);
+    }
+}
+
+/// Session with the long-poll endpoint.
+#[derive(Debug)]
+pub(crate) struct LongPoll {
+    session: Arc<Session>,
+    /// Messages waiting to be sent in the next batch, with the sender of the outcome.
+    batch: Mutex<Vec<Queued>>,
+    /// Id of the next queued message.
+    next_queued: AtomicU64,
+    /// Sends one batch at the time.
+    sending: tokio::sync::Mutex<()>,
+    /// Receives one poll at the time.
+    receiving: tokio::sync::Mutex<Receiving>,
+    closed: AtomicBool,
+}
+
+/// Requests to the endpoint, shared with the poll in progress.
+#[derive(Debug)]
+struct Session {
+    dialer: Dialer,
+    /// Endpoint with the connect params, without the session token.
+    endpoint: Uri,
+    headers: HeaderMap,
+    token: Mutex<String>,
+    /// Connections kept alive after a response.
+    idle: Mutex<Vec<BoxIo>>,
+}
+
+/// State of the polls, kept between the calls to [`LongPoll::next`] to be cancel safe.
+#[derive(Default)]
+struct Receiving {
+    /// Messages of the last poll not yet received.
+    messages: VecDeque<String>,
+    /// Poll in progress, resumed if the future receiving the message was dropped.
+    poll: Option<BoxFuture<'static, Result<Vec<String>, Error>>>,
+}
+
+impl LongPoll {
+    /// Opens a new session, returning it with the response of the server.
+    #[instrument(skip_all, fields(endpoint = %uri))]
+    pub(crate) async fn open(
+        dialer: Dialer,
+        uri: &Uri,
+        mut headers: HeaderMap,
+        auth_token: Option<&str>,
+    ) -> Result<(Self, Handshake), Error> {
+        let endpoint = endpoint(uri)?;
+
+        if let Some(token) = auth_token {
+            let value =
+                HeaderValue::try_from(token).map_err(|err| Error::LongPoll(invalid_data(err)))?;
+
+            headers.insert(HeaderName::from_static(AUTH_TOKEN_HEADER), value);
+        }
+
+        let session = Session {
+            dialer,
+            endpoint,
+            headers,
+            token: Mutex::new(String::new()),
+            idle: Mutex::new(Vec::new()),
+        };
+
+        let resp = session.request("GET", None).await?;
+        let poll = PollResponse::parse(&resp)?;
+        let status = poll.status();
+
+        // The server creates the session and returns its token with the gone status
+        let (StatusCode::GONE, Some(token)) = (status, poll.token) else {
+            return Err(rejected(status, resp));
+        };
+
+        debug!("long-poll session opened");
+
+        *lock(&session.token) = token;
+
+        let long_poll = Self {
+            session: Arc::new(session),
+            batch: Mutex::new(Vec::new()),
+            next_queued: AtomicU64::new(0),
+            sending: tokio::sync::Mutex::new(()),
+            receiving: tokio::sync::Mutex::new(Receiving::default()),
+            closed: AtomicBool::new(false),
+        };
+
+        Ok((long_poll, Handshake::from_parts(resp.status, resp.headers)))
+    }
+
+    /// Sends the message in the next batch.
+    ///
+    /// The messages queued while a batch is being sent are sent together in the next one, and
+    /// each task returns the outcome of the batch with its message. If the task is cancelled
+    /// before the batch with its message is sent, the message is removed from it.
+    #[instrument(skip_all)]
+    async fn send_batched(&self, frame: tungstenite::Message) -> Result<(), Error> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Disconnected);
+        }
+
+        let tungstenite::Message::Text(text) = frame else {
+            return Err(Error::LongPollBinary);
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_queued.fetch_add(1, Ordering::Relaxed);
+
+        lock(&self.batch).push(Queued {
+            id,
+            text: text.to_string(),
+            tx,
+        });
+
+        {
+            let _dequeue = Dequeue {
+                batch: &self.batch,
+                id,
+            };
+            let _sending = self.sending.lock().await;
+
+            let batch = std::mem::take(&mut *lock(&self.batch));
+
+            // Otherwise the message was sent with the batch of another task
+            if !batch.is_empty() {
+                let (messages, senders): (Vec<_>, Vec<_>) = batch
+                    .into_iter()
+                    .map(|queued| (queued.text, queued.tx))
+                    .unzip();
+
+                trace!(count = messages.len(), "sending batch");
+
+                let res = self
+                    .session
+                    .post(messages.join("\n"))
+                    .await
+                    .map_err(Arc::new);
+
+                for tx in senders {
+                    // The task could have been cancelled
+                    let _ = tx.send(res.clone());
+                }
+            }
+        }
+
+        match rx.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(Arc::try_unwrap(err).unwrap_or_else(|err| shared(&err))),
+            Err(_) => Err(Error::LongPoll(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "the batch was cancelled while sending",
+            ))),
+        }
+    }
+
+    /// Returns the next message, polling the server if none was already received.
+    ///
+    /// The poll in progress is kept if the future is dropped, so no message is lost.
+    async fn next(&self) -> Result<String, Error> {
+        let mut receiving = self.receiving.lock().await;
+
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return Err(Error::Disconnected);
+            }
+
+            if let Some(msg) = receiving.messages.pop_front() {
+                return Ok(msg);
+            }
+
+            let session = Arc::clone(&self.session);
+            let poll = receiving
+                .poll
+                .get_or_insert_with(|| async move { session.poll().await }.boxed());
+
+            let res = poll.await;
+
+            receiving.poll = None;
+            receiving.messages.extend(res?);
+        }
+    }
+}
+
+impl Session {
+    /// Polls the messages of the session, returns [`Error::Disconnected`] if it expired.
+    #[instrument(skip_all)]
+    async fn poll(&self) -> Result<Vec<String>, Error> {
+        let resp = self.request("GET", None).await?;
+        let poll = PollResponse::parse(&resp)?;
+
+        if let Some(token) = &poll.token {
+            lock(&self.token).clone_from(token);
+        }
+
+        match poll.status() {
+            StatusCode::OK => {
+                trace!(count = poll.messages.len(), "messages received");
+
+                Ok(poll.messages)
+            }
+            StatusCode::NO_CONTENT => Ok(Vec::new()),
+            StatusCode::GONE => {
+                debug!("long-poll session expired");
+
+                Err(Error::Disconnected)
+            }
+            status => Err(rejected(status, resp)),
+        }
+    }
+
+    /// Sends the batch of newline delimited messages.
+    async fn post(&self, batch: String) -> Result<(), Error> {
+        let resp = self.request("POST", Some(batch)).await?;
+        let poll = PollResponse::parse(&resp)?;
+
+        match poll.status() {
+            StatusCode::OK => Ok(()),
+            StatusCode::GONE => Err(Error::Disconnected),
+            status => Err(rejected(status, resp)),
+        }
+    }
+
+    /// Sends the request to the endpoint with the session token.
+    ///
+    /// An idle connection is reused if available, and the request is sent again on a new one if
+    /// the server already closed it.
+    async fn request(&self, method: &str, body: Option<String>) -> Result<Response, Error> {
+        let token = lock(&self.token).clone();
+
+        let uri = if token.is_empty() {
+            self.endpoint.clone()
+        } else {
+            let query = serde_urlencoded::to_string([("token", token)]).map_err(Error::Params)?;
+
+            append_query(&self.endpoint, &query)?
+        };
+
+        let request = self.encode(&uri, method, body.as_deref().unwrap_or_default())?;
+
+        let idle = lock(&self.idle).pop();
+
+        if let Some(mut stream) = idle {
+            match self.exchange(&mut stream, &request).await? {
+                Ok((resp, keep_alive)) => return Ok(self.release(stream, resp, keep_alive)),
+                Err(Failure::Closed(err)) => {
+                    trace!(error = %err, "idle connection closed, sending on a new one");
+                }
+                Err(Failure::Error(err)) => return Err(err),
+            }
+        }
+
+        let mut stream = self.dialer.dial(&uri).await?;
+
+        match self.exchange(&mut stream, &request).await? {
+            Ok((resp, keep_alive)) => Ok(self.release(stream, resp, keep_alive)),
+            Err(Failure::Closed(err)) => Err(Error::LongPoll(err)),
+            Err(Failure::Error(err)) => Err(err),
+        }
+    }
+
+    /// Exchanges the request on the connection, failing if the response doesn't complete in time.
+    ///
+    /// On timeout the connection is dropped, since the response could still be received on it.
+    async fn exchange(
+        &self,
+        stream: &mut BoxIo,
+        request: &[u8],
+    ) -> Result<Result<(Response, bool), Failure>, Error> {
+        with_timeout(
+            ConnectStep::LongPoll,
+            self.dialer.timeouts.long_poll,
+            async { Ok(exchange(stream, request).await) },
+        )
+        .await
+    }
+
+    /// Encodes the HTTP request with the headers of the session.
+    fn encode(&self, uri: &Uri, method: &str, body: &str) -> Result<Vec<u8>, Error> {
+        let host = uri.authority().map_or("", |authority| authority.as_str());
+        let path = uri.path_and_query().map_or("/", |pq| pq.as_str());
+
+        let mut request =
+            format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\nAccept: application/json\r\n");
+
+        for (name, value) in &self.headers {
+            let value = value
+                .to_str()
+                .map_err(|err| Error::LongPoll(invalid_data(err)))?;
+
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+
+        if method == "POST" {
+            request.push_str(&format!(
+                "Content-Type: application/x-ndjson\r\nContent-Length: {}\r\n",
+                body.len()
+            ));
+        }
+
+        request.push_str("\r\n");
+        request.push_str(body);
+
+        Ok(request.into_bytes())
+    }
+
+    /// Keeps the connection for the next request, if the server didn't close it.
+    fn release(&self, stream: BoxIo, resp: Response, keep_alive: bool) -> Response {
+        if keep_alive {
+            let mut idle = lock(&self.idle);
+
+            if idle.len() < MAX_IDLE {
+                idle.push(stream);
+            }
+        }
+
+        resp
+    }
+}
+
+/// Sends the request and reads the response, returning if the connection can be reused.
+async fn exchange(stream: &mut BoxIo, request: &[u8]) -> Result<(Response, bool), Failure> {
+    stream.write_all(request).await.map_err(Failure::Closed)?;
+    stream.flush().await.map_err(Failure::Closed)?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let head = loop {
+        if let Some(head) = Head::parse(&buf).map_err(Failure::Error)? {
+            break head;
+        }
+
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(Failure::Error(Error::LongPoll(invalid_data(
+                "response head too long",
+            ))));
+        }
+
+        let read = match stream.read(&mut chunk).await {
+            Ok(0) if buf.is_empty() => {
+                return Err(Failure::Closed(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            Ok(0) => return Err(Failure::Error(Error::LongPoll(unexpected_eof()))),
+            Ok(read) => read,
+            Err(err) if buf.is_empty() => return Err(Failure::Closed(err)),
+            Err(err) => return Err(Failure::Error(Error::LongPoll(err))),
+        };
+
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let chunked = head
+        .headers
+        .get(header::TRANSFER_ENCODING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"chunked"));
+    let content_length = head
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .ok_or_else(|| Error::LongPoll(invalid_data("invalid content length")))
+        })
+        .transpose()
+        .map_err(Failure::Error)?;
+
+    if content_length.is_some_and(|len| len > MAX_BODY_LEN) {
+        return Err(Failure::Error(Error::LongPoll(invalid_data(
+            "response body too long",
+        ))));
+    }
+
+    // Without a length the body ends when the server closes the connection
+    let mut keep_alive = head.keep_alive && (chunked || content_length.is_some());
+
+    // The bytes read after the head, not yet consumed by the body
+    let mut received = buf.split_off(head.len);
+    let mut decoder = chunked.then(ChunkedBody::default);
+
+    let body = loop {
+        let body = match (&mut decoder, content_length) {
+            (Some(decoder), _) => {
+                let consumed = decoder.decode(&received).map_err(Failure::Error)?;
+
+                received.drain(..consumed);
+
+                decoder
+                    .is_done()
+                    .then(|| (std::mem::take(&mut decoder.body), 0))
+            }
+            (None, Some(len)) => (received.len() >= len).then(|| (received[..len].to_vec(), len)),
+            (None, None) => None,
+        };
+
+        if let Some((body, len)) = body {
+            // Unexpected data after the response
+            if received.len() > len {
+                keep_alive = false;
+            }
+
+            break body;
+        }
+
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|err| Failure::Error(Error::LongPoll(err)))?;
+
+        if read == 0 {
+            if chunked || content_length.is_some() {
+                return Err(Failure::Error(Error::LongPoll(unexpected_eof())));
+            }
+
+            break received;
+        }
+
+        if decoder.is_none() && received.len() > MAX_BODY_LEN {
+            return Err(Failure::Error(Error::LongPoll(invalid_data(
+                "response body too long",
+            ))));
+        }
+
+        received.extend_from_slice(&chunk[..read]);
+    };
+
+    let resp = Response {
+        status: head.status,
+        headers: head.headers,
+        body,
+    };
+
+    Ok((resp, keep_alive))
+}
+
+impl PollResponse {
+    fn parse(resp: &Response) -> Result<Self, Error> {
+        if resp.status != StatusCode::OK {
+            return Err(Error::Rejected {
+                status: resp.status,
+                headers: Box::new(resp.headers.clone()),
+                body: Some(resp.body.clone()),
+            });
+        }
+
+        serde_json::from_slice(&resp.body).map_err(|err| Error::LongPoll(invalid_data(err)))
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl Head {
+    /// Parses the head of the response, returns [`None`] if incomplete.
+    fn parse(response: &[u8]) -> Result<Option<Self>, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+
+        let httparse::Status::Complete(len) = parsed
+            .parse(response)
+            .map_err(|err| Error::LongPoll(invalid_data(err)))?
+        else {
+            return Ok(None);
+        };
+
+        let status = parsed
+            .code
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .ok_or_else(|| Error::LongPoll(invalid_data("missing status code")))?;
+
+        let mut header_map = HeaderMap::new();
+
+        for header in parsed.headers.iter() {
+            let name = HeaderName::try_from(header.name)
+                .map_err(|err| Error::LongPoll(invalid_data(err)))?;
+            let value = HeaderValue::try_from(header.value)
+                .map_err(|err| Error::LongPoll(invalid_data(err)))?;
+
+            header_map.append(name, value);
+        }
+
+        let close = header_map
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("close"));
+
+        Ok(Some(Self {
+            len,
+            status,
+            headers: header_map,
+            keep_alive: parsed.version == Some(1) && !close,
+        }))
+    }
+}
+
+impl Transport for LongPoll {
+    fn send(&self, frame: tungstenite::Message) -> BoxFuture<'_, Result<(), SendError>> {
+        async move { self.send_batched(frame).await.map_err(SendError::from) }.boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<Result<tungstenite::Message, Error>>> {
+        async move {
+            match self.next().await {
+                Ok(msg) => Some(Ok(tungstenite::Message::Text(msg.into()))),
+                Err(Error::Disconnected) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }
+        .boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<(), Error>> {
+        // The server drops the session once it's not polled anymore
+        debug!("closing the long-poll session");
+
+        self.closed.store(true, Ordering::Release);
+
+        lock(&self.session.idle).clear();
+
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+impl std::fmt::Debug for Receiving {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Receiving")
+            .field("messages", &self.messages)
+            .field("poll", &self.poll.is_some())
+            .finish()
+    }
+}
+
+/// Returns the long-poll endpoint for the URI of the WebSocket.
+///
+/// Like phoenix.js, the `websocket` segment at the end of the path is replaced with `longpoll`.
+fn endpoint(uri: &Uri) -> Result<Uri, Error> {
+    let path = uri.path().trim_end_matches('/');
+    let path = path.strip_suffix("/websocket").unwrap_or(path);
+
+    let pq = match uri.query() {
+        Some(query) => format!("{path}/longpoll?{query}"),
+        None => format!("{path}/longpoll"),
+    };
+
+    let pq = tungstenite::http::uri::PathAndQuery::try_from(pq).map_err(Error::Uri)?;
+
+    tungstenite::http::uri::Builder::from(uri.clone())
+        .path_and_query(pq)
+        .build()
+        .map_err(Error::UriBuild)
+}
+
+/// Decoder of a chunked body, fed with the bytes as they are read.
+#[derive(Debug, Default)]
+struct ChunkedBody {
+    state: ChunkState,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum ChunkState {
+    /// Reading the line with the size of the next chunk.
+    #[default]
+    Size,
+    /// Reading the data of the chunk, with the number of bytes left.
+    Data(usize),
+    /// Reading the line break at the end of the data.
+    DataEnd,
+    /// Reading the trailers after the last chunk, until the empty line.
+    Trailers,
+    Done,
+}
+
+impl ChunkedBody {
+    /// Decodes the bytes, returns the number of bytes consumed.
+    ///
+    /// The bytes not consumed are the start of an incomplete line, and must be passed again with
+    /// the next ones.
+    fn decode(&mut self, input: &[u8]) -> Result<usize, Error> {
+        let mut pos = 0;
+
+        loop {
+            let rest = &input[pos..];
+
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line_end) = line_len(rest)? else {
+                        break;
+                    };
+
+                    // Ignore the chunk extensions
+                    let size = std::str::from_utf8(&rest[..line_end])
+                        .ok()
+                        .and_then(|line| line.split(';').next())
+                        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                        .ok_or_else(|| Error::LongPoll(invalid_data("invalid chunk size")))?;
+
+                    if size > MAX_BODY_LEN - self.body.len() {
+                        return Err(Error::LongPoll(invalid_data("response body too long")));
+                    }
+
+                    pos += line_end + 2;
+
+                    self.state = if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size)
+                    };
+                }
+                ChunkState::Data(left) => {
+                    if rest.is_empty() {
+                        break;
+                    }
+
+                    let len = left.min(rest.len());
+
+                    self.body.extend_from_slice(&rest[..len]);
+                    pos += len;
+
+                    self.state = if len == left {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(left - len)
+                    };
+                }
+                ChunkState::DataEnd => match rest.get(..2) {
+                    Some(b"\r\n") => {
+                        pos += 2;
+                        self.state = ChunkState::Size;
+                    }
+                    Some(_) => return Err(Error::LongPoll(invalid_data("invalid chunk end"))),
+                    None => break,
+                },
+                ChunkState::Trailers => {
+                    let Some(line_end) = line_len(rest)? else {
+                        break;
+                    };
+
+                    pos += line_end + 2;
+
+                    if line_end == 0 {
+                        self.state = ChunkState::Done;
+                    }
+                }
+                ChunkState::Done => break,
+            }
+        }
+
+        Ok(pos)
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done)
+    }
+}
+
+/// Returns the length of the line before the line break, [`None`] if incomplete.
+fn line_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    match find_crlf(buf) {
+        Some(len) => Ok(Some(len)),
+        // Avoids searching the line again in a stream without line breaks
+        None if buf.len() > MAX_HEAD_LEN => Err(Error::LongPoll(invalid_data("line too long"))),
+        None => Ok(None),
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+fn rejected(status: StatusCode, resp: Response) -> Error {
+    Error::Rejected {
+        status,
+        headers: Box::new(resp.headers),
+        body: Some(resp.body),
+    }
+}
+
+/// Returns the error of a batch to the other tasks with a message in it.
+fn shared(err: &Arc<Error>) -> Error {
+    match err.as_ref() {
+        Error::Disconnected => Error::Disconnected,
+        _ => Error::LongPoll(std::io::Error::other(Arc::clone(err))),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn unexpected_eof() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "connection closed before the end of the response",
+    )
+}
+
+fn invalid_data<E>(err: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::Builder;
+    use crate::message::MessageKind;
+
+    use super::*;
+
+    /// Reads the request, returning the request line and the body.
+    async fn read_request(stream: &mut TcpStream) -> (String, String) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+
+        let head_len = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "connection closed");
+            request.extend_from_slice(&buf[..n]);
+
+            if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let head = String::from_utf8(request[..head_len].to_vec()).unwrap();
+        let content_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+
+        while request.len() < head_len + content_length {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        let line = head.lines().next().unwrap().to_string();
+        let body = String::from_utf8(request[head_len..].to_vec()).unwrap();
+
+        (line, body)
+    }
+
+    /// Writes the JSON body, alternating between a chunked and a sized one.
+    async fn respond(stream: &mut TcpStream, chunked: bool, body: &str) {
+        let resp = if chunked {
+            format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{body}\r\n0\r\n\r\n",
+                body.len()
+            )
+        } else {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+
+        stream.write_all(resp.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_poll_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let reply = r#"["1","1","room:lobby","phx_reply",{"status":"ok","response":{}}]"#;
+        let script = [
+            (
+                "GET /socket/longpoll?vsn=2.0.0 HTTP/1.1",
+                "",
+                r#"{"status":410,"token":"t1"}"#.to_string(),
+            ),
+            (
+                "POST /socket/longpoll?vsn=2.0.0&token=t1 HTTP/1.1",
+                r#"["1","1","room:lobby","phx_join",{}]"#,
+                r#"{"status":200}"#.to_string(),
+            ),
+            (
+                "GET /socket/longpoll?vsn=2.0.0&token=t1 HTTP/1.1",
+                "",
+                r#"{"status":204}"#.to_string(),
+            ),
+            (
+                "GET /socket/longpoll?vsn=2.0.0&token=t1 HTTP/1.1",
+                "",
+                serde_json::json!({"status": 200, "token": "t2", "messages": [reply]}).to_string(),
+            ),
+            (
+                "POST /socket/longpoll?vsn=2.0.0&token=t2 HTTP/1.1",
+                r#"["1","2","room:lobby","ping",null]"#,
+                r#"{"status":410}"#.to_string(),
+            ),
+            (
+                "GET /socket/longpoll?vsn=2.0.0&token=t2 HTTP/1.1",
+                "",
+                r#"{"status":410}"#.to_string(),
+            ),
+        ];
+
+        let server = tokio::spawn(async move {
+            // The requests are sent on the same connection
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            for (i, (line, body, resp)) in script.into_iter().enumerate() {
+                assert_eq!(
+                    read_request(&mut stream).await,
+                    (line.to_string(), body.to_string())
+                );
+
+                respond(&mut stream, i % 2 == 0, &resp).await;
+            }
+        });
+
+        let uri = format!("ws://{addr}/socket/websocket").parse().unwrap();
+        let client = Builder::new(uri)
+            .unwrap()
+            .long_poll()
+            .connect()
+            .await
+            .unwrap();
+
+        client.join("room:lobby").await.unwrap();
+
+        let msg = client.recv::<serde_json::Value>().await.unwrap();
+
+        assert_eq!(msg.kind(), MessageKind::Reply);
+        assert_eq!(msg.topic_name, "room:lobby");
+
+        assert!(matches!(
+            client.send("room:lobby", "ping", ()).await,
+            Err(Error::Disconnected)
+        ));
+        assert!(matches!(
+            client.recv::<serde_json::Value>().await,
+            Err(Error::Disconnected)
+        ));
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_poll_request_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            read_request(&mut stream).await;
+            respond(&mut stream, false, r#"{"status":410,"token":"t1"}"#).await;
+
+            // Never responds to the poll, while the heartbeat is sent on a new connection
+            let (mut heartbeat, _) = listener.accept().await.unwrap();
+
+            read_request(&mut heartbeat).await;
+            respond(&mut heartbeat, false, r#"{"status":200}"#).await;
+
+            (stream, heartbeat)
+        });
+
+        let timeout = Duration::from_millis(50);
+        let uri = format!("ws://{addr}/socket/websocket").parse().unwrap();
+        let client = Builder::new(uri)
+            .unwrap()
+            .long_poll()
+            .long_poll_timeout(timeout)
+            .connect()
+            .await
+            .unwrap();
+
+        let res = client.recv::<serde_json::Value>().await;
+
+        assert!(
+            matches!(
+                res,
+                Err(Error::Timeout {
+                    step: ConnectStep::LongPoll,
+                    timeout: t,
+                }) if t == timeout
+            ),
+            "{res:?}"
+        );
+
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn drop_cancelled_messages_from_the_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (posted_tx, posted_rx) = oneshot::channel();
+        let (respond_tx, respond_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            read_request(&mut stream).await;
+            respond(&mut stream, false, r#"{"status":410,"token":"t1"}"#).await;
+
+            let mut batches = Vec::new();
+
+            let (_, body) = read_request(&mut stream).await;
+            batches.push(body);
+
+            // Holds the first batch, while the second message is queued and cancelled
+            posted_tx.send(()).unwrap();
+            respond_rx.await.unwrap();
+            respond(&mut stream, false, r#"{"status":200}"#).await;
+
+            let (_, body) = read_request(&mut stream).await;
+            batches.push(body);
+            respond(&mut stream, false, r#"{"status":200}"#).await;
+
+            batches
+        });
+
+        let uri = format!("ws://{addr}/socket/websocket").parse().unwrap();
+        let client = Arc::new(
+            Builder::new(uri)
+                .unwrap()
+                .long_poll()
+                .connect()
+                .await
+                .unwrap(),
+        );
+
+        let first = tokio::spawn({
+            let client = Arc::clone(&client);
+
+            async move { client.send("room:lobby", "first", ()).await }
+        });
+
+        posted_rx.await.unwrap();
+
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            client.send("room:lobby", "cancelled", ()),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        respond_tx.send(()).unwrap();
+        first.await.unwrap().unwrap();
+
+        client.send("room:lobby", "last", ()).await.unwrap();
+
+        let events: Vec<Vec<String>> = server
+            .await
+            .unwrap()
+            .iter()
+            .map(|batch| {
+                batch
+                    .lines()
+                    .map(|line| {
+                        let msg: serde_json::Value = serde_json::from_str(line).unwrap();
+
+                        msg[3].as_str().unwrap().to_string()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(events, [vec!["first"], vec!["last"]]);
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_long_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            // Two WebSocket upgrades, then the long-poll sessions of two connections
+            for _ in 0..4 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let (line, _) = read_request(&mut stream).await;
+
+                if line.starts_with("GET /socket/websocket") {
+                    stream
+                        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                } else {
+                    respond(&mut stream, false, r#"{"status":410,"token":"t1"}"#).await;
+                }
+
+                requests.push(line);
+            }
+
+            requests
+        });
+
+        let uri = format!("ws://{addr}/socket/websocket").parse().unwrap();
+        let builder = Builder::new(uri).unwrap().long_poll_fallback(2);
+
+        let res = builder.connect().await;
+
+        assert!(
+            matches!(&res, Err(Error::Rejected { status, .. }) if *status == StatusCode::BAD_REQUEST),
+            "{res:?}"
+        );
+
+        // Falls back after the second failure, then keeps using the long-poll
+        builder.connect().await.unwrap();
+        builder.connect().await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "GET /socket/websocket?vsn=2.0.0 HTTP/1.1",
+                "GET /socket/websocket?vsn=2.0.0 HTTP/1.1",
+                "GET /socket/longpoll?vsn=2.0.0 HTTP/1.1",
+                "GET /socket/longpoll?vsn=2.0.0 HTTP/1.1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_too_long_responses() {
+        let responses = [
+            format!(
+                "HTTP/1.1 200 OK\r\nX-Padding: {}\r\n",
+                "a".repeat(MAX_HEAD_LEN)
+            ),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY_LEN + 1
+            ),
+        ];
+
+        for resp in responses {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+
+            server.write_all(resp.as_bytes()).await.unwrap();
+
+            let mut stream: BoxIo = Box::new(async_tungstenite::tokio::TokioAdapter::new(client));
+
+            let res = exchange(&mut stream, b"GET / HTTP/1.1\r\n\r\n").await;
+
+            let Err(Failure::Error(Error::LongPoll(err))) = res else {
+                panic!("expected a long-poll error");
+            };
+
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn decode_chunked_body_incrementally() {
+        let encoded = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: 1\r\n\r\n";
+
+        // Fed one byte at the time, keeping the bytes not consumed
+        let mut decoder = ChunkedBody::default();
+        let mut pending = Vec::new();
+
+        for byte in encoded {
+            pending.push(*byte);
+
+            let consumed = decoder.decode(&pending).unwrap();
+            pending.drain(..consumed);
+        }
+
+        assert!(decoder.is_done());
+        assert!(pending.is_empty());
+        assert_eq!(decoder.body, b"hello, world");
+
+        let mut decoder = ChunkedBody::default();
+
+        assert!(decoder.decode(b"5\r\nhello!!").is_err());
+    }
+
+    #[test]
+    fn long_poll_endpoint() {
+        let uri = Uri::from_static("wss://example.com/socket/websocket?vsn=2.0.0");
+
+        assert_eq!(
+            endpoint(&uri).unwrap(),
+            "wss://example.com/socket/longpoll?vsn=2.0.0"
+        );
+
+        let uri = Uri::from_static("ws://localhost:4000/live/");
+
+        assert_eq!(endpoint(&uri).unwrap(), "ws://localhost:4000/live/longpoll");
+    }
+}
@@ -2,10 +2,15 @@
 
 use std::borrow::Cow;
 use std::fmt::{Debug, Display};
+use std::io::Write;
 
+use bytes::Bytes;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tungstenite::Utf8Bytes;
 
+use crate::Error;
 use crate::client::Id;
 
 /// Message received from the channel.
@@ -35,12 +40,37 @@ pub struct Message<P> {
 }
 
 impl<P> Message<P> {
+    /// Returns the kind of the message, following the Phoenix protocol rules.
+    pub fn kind(&self) -> MessageKind {
+        MessageKind::classify(
+            &self.event_name,
+            self.join_reference.is_some(),
+            self.message_reference.is_some(),
+        )
+    }
+
+    /// Returns the control event, if the event is one of the Phoenix reserved events.
+    pub fn control_event(&self) -> Option<ControlEvent> {
+        ControlEvent::from_event(&self.event_name)
+    }
+
     pub(crate) fn info(&self) -> String {
         format!(
             "[{:?}, {:?}, {:?}, {:?}, <payload>]",
             self.join_reference, self.message_reference, self.topic_name, self.event_name
         )
     }
+
+    /// Returns the message with the payload replaced.
+    pub(crate) fn with_payload<T>(self, payload: T) -> Message<T> {
+        Message {
+            join_reference: self.join_reference,
+            message_reference: self.message_reference,
+            topic_name: self.topic_name,
+            event_name: self.event_name,
+            payload,
+        }
+    }
 }
 
 impl<'a, P> From<ChannelMsg<'a, P>> for Message<P> {
@@ -74,6 +104,95 @@ impl Message<serde_json::Value> {
             payload,
         })
     }
+
+    /// Decodes the payload of a `phx_reply` in the response of a successful reply.
+    ///
+    /// Returns [`Error::ReplyError`] if the server replied with an error status, or
+    /// [`Error::NotReply`] if the message is not a `phx_reply`.
+    pub fn into_reply<T>(mut self) -> Result<Message<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        if self.kind() != MessageKind::Reply {
+            return Err(Error::NotReply(self.with_payload(())));
+        }
+
+        let reply =
+            serde_json::from_value::<Reply<T>>(self.payload.take()).map_err(Error::Deserialize)?;
+        let msg = self.with_payload(());
+
+        match reply {
+            Reply::Ok(response) => Ok(msg.with_payload(response)),
+            Reply::Error(response) => Err(Error::ReplyError {
+                msg,
+                response: Box::new(response),
+            }),
+        }
+    }
+}
+
+impl Message<Box<RawValue>> {
+    /// Deserialize the raw payload in a specific payload type.
+    ///
+    /// The raw message is kept, so the payload can be deserialized again in a different type.
+    pub fn deserialize_payload<P>(&self) -> Result<Message<P>, serde_json::error::Error>
+    where
+        P: DeserializeOwned,
+    {
+        let payload = serde_json::from_str(self.payload.get())?;
+
+        Ok(Message {
+            join_reference: self.join_reference.clone(),
+            message_reference: self.message_reference.clone(),
+            topic_name: self.topic_name.clone(),
+            event_name: self.event_name.clone(),
+            payload,
+        })
+    }
+}
+
+impl<T, E> Message<Reply<T, E>> {
+    /// Returns the response of the reply, or the error response.
+    pub fn into_result(self) -> Result<T, E> {
+        self.payload.into_result()
+    }
+}
+
+/// Payload of a `phx_reply` message.
+///
+/// The server replies to a message with a status and a response, like:
+///
+/// ```json
+/// {"status": "ok", "response": {}}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "response", rename_all = "lowercase")]
+pub enum Reply<T, E = serde_json::Value> {
+    /// The message was handled successfully.
+    Ok(T),
+    /// The message was rejected by the server.
+    Error(E),
+}
+
+impl<T, E> Reply<T, E> {
+    /// Converts the reply into a [`Result`].
+    pub fn into_result(self) -> Result<T, E> {
+        match self {
+            Reply::Ok(response) => Ok(response),
+            Reply::Error(response) => Err(response),
+        }
+    }
+
+    /// Returns true if the reply has an ok status.
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Reply::Ok(_))
+    }
+}
+
+impl<T, E> From<Reply<T, E>> for Result<T, E> {
+    fn from(value: Reply<T, E>) -> Self {
+        value.into_result()
+    }
 }
 
 impl<P> Display for Message<P>
@@ -106,6 +225,400 @@ where
     }
 }
 
+/// Kind of a message, see [`Message::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MessageKind {
+    /// Reply of the server to a message sent by the client.
+    Reply,
+    /// Event reserved by Phoenix, other than the reply.
+    Control(ControlEvent),
+    /// Message pushed by the server to the joined channel, it has a join reference but no message
+    /// reference.
+    Push,
+    /// Message broadcasted on the topic, without references.
+    Broadcast,
+}
+
+impl MessageKind {
+    fn classify(event: &str, join_reference: bool, message_reference: bool) -> Self {
+        match ControlEvent::from_event(event) {
+            Some(ControlEvent::Reply) => MessageKind::Reply,
+            Some(control) => MessageKind::Control(control),
+            None if join_reference || message_reference => MessageKind::Push,
+            None => MessageKind::Broadcast,
+        }
+    }
+}
+
+/// Events reserved by Phoenix.
+///
+/// See <https://github.com/phoenixframework/phoenix/blob/ad1a7ee2c9c29ff102b94242fdbb9cb14dd0dd4b/assets/js/phoenix/constants.js>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ControlEvent {
+    /// The `phx_join` event.
+    Join,
+    /// The `phx_leave` event.
+    Leave,
+    /// The `phx_reply` event.
+    Reply,
+    /// The `phx_error` event, sent when the channel crashed.
+    Error,
+    /// The `phx_close` event, sent when the channel is closed.
+    Close,
+    /// The `heartbeat` event.
+    Heartbeat,
+    /// The `presence_state` event of Phoenix Presence.
+    PresenceState,
+    /// The `presence_diff` event of Phoenix Presence.
+    PresenceDiff,
+}
+
+impl ControlEvent {
+    /// Returns the control event for the event name.
+    pub fn from_event(event: &str) -> Option<Self> {
+        let control = match event {
+            "phx_join" => ControlEvent::Join,
+            "phx_leave" => ControlEvent::Leave,
+            "phx_reply" => ControlEvent::Reply,
+            "phx_error" => ControlEvent::Error,
+            "phx_close" => ControlEvent::Close,
+            "heartbeat" => ControlEvent::Heartbeat,
+            "presence_state" => ControlEvent::PresenceState,
+            "presence_diff" => ControlEvent::PresenceDiff,
+            _ => return None,
+        };
+
+        Some(control)
+    }
+
+    /// Returns the event name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlEvent::Join => "phx_join",
+            ControlEvent::Leave => "phx_leave",
+            ControlEvent::Reply => "phx_reply",
+            ControlEvent::Error => "phx_error",
+            ControlEvent::Close => "phx_close",
+            ControlEvent::Heartbeat => "heartbeat",
+            ControlEvent::PresenceState => "presence_state",
+            ControlEvent::PresenceDiff => "presence_diff",
+        }
+    }
+}
+
+impl Display for ControlEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Frame received from the WebSocket, returned by [`Client::recv_raw`](crate::Client::recv_raw).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    text: Utf8Bytes,
+}
+
+impl RawFrame {
+    pub(crate) fn new(text: Utf8Bytes) -> Self {
+        Self { text }
+    }
+
+    /// Parses the message borrowing the fields from the frame.
+    ///
+    /// The payload is not deserialized.
+    pub fn message(&self) -> Result<RawMessage<'_>, Error> {
+        serde_json::from_str(self.text.as_str()).map_err(Error::Deserialize)
+    }
+
+    /// Returns the text of the frame.
+    pub fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
+}
+
+/// Message borrowed from a [`RawFrame`], with the payload not deserialized.
+///
+/// The topic and event names are only allocated if they contain escaped characters.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RawMessage<'a> {
+    /// The `join_reference` of the [`Message`].
+    pub join_reference: Option<Cow<'a, str>>,
+    /// The `message_reference` of the [`Message`].
+    pub message_reference: Option<Cow<'a, str>>,
+    /// The `topic_name` of the [`Message`].
+    pub topic_name: Cow<'a, str>,
+    /// The `event_name` of the [`Message`].
+    pub event_name: Cow<'a, str>,
+    /// The raw JSON of the `payload`.
+    pub payload: &'a RawValue,
+}
+
+impl<'a> RawMessage<'a> {
+    /// Returns the kind of the message, following the Phoenix protocol rules.
+    pub fn kind(&self) -> MessageKind {
+        MessageKind::classify(
+            &self.event_name,
+            self.join_reference.is_some(),
+            self.message_reference.is_some(),
+        )
+    }
+
+    /// Deserialize the payload in a specific type.
+    pub fn deserialize_payload<P>(&self) -> Result<P, serde_json::Error>
+    where
+        P: Deserialize<'a>,
+    {
+        serde_json::from_str(self.payload.get())
+    }
+
+    /// Returns an owned message with the raw payload.
+    pub fn to_message(&self) -> Message<Box<RawValue>> {
+        self.with_payload(self.payload.to_owned())
+    }
+
+    pub(crate) fn with_payload<T>(&self, payload: T) -> Message<T> {
+        Message {
+            join_reference: self.join_reference.as_deref().map(str::to_string),
+            message_reference: self.message_reference.as_deref().map(str::to_string),
+            topic_name: self.topic_name.to_string(),
+            event_name: self.event_name.to_string(),
+            payload,
+        }
+    }
+
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "[{:?}, {:?}, {:?}, {:?}, <payload>]",
+            self.join_reference, self.message_reference, self.topic_name, self.event_name
+        )
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for RawMessage<'a> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Borrowed<'a>(
+            #[serde(borrow, deserialize_with = "borrow_reference")] Option<Cow<'a, str>>,
+            #[serde(borrow, deserialize_with = "borrow_reference")] Option<Cow<'a, str>>,
+            #[serde(borrow)] Cow<'a, str>,
+            #[serde(borrow)] Cow<'a, str>,
+            #[serde(borrow)] &'a RawValue,
+        );
+
+        let Borrowed(join_reference, message_reference, topic_name, event_name, payload) =
+            Deserialize::deserialize(deserializer)?;
+
+        Ok(Self {
+            join_reference,
+            message_reference,
+            topic_name,
+            event_name,
+            payload,
+        })
+    }
+}
+
+/// Borrows the optional reference, since serde only borrows a [`Cow`] that is not nested.
+fn borrow_reference<'de: 'a, 'a, D>(deserializer: D) -> Result<Option<Cow<'a, str>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Reference<'a>(#[serde(borrow)] Cow<'a, str>);
+
+    Option::<Reference>::deserialize(deserializer).map(|reference| reference.map(|r| r.0))
+}
+
+/// Kind of a binary push message sent by the client.
+const BINARY_KIND_PUSH: u8 = 0;
+/// Length of the kind and of the fields sizes.
+const BINARY_HEADER_LEN: usize = 5;
+
+/// Message sent by the client.
+///
+/// The references are written directly in the frame when encoded, without allocating them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Push<'a, P> {
+    pub(crate) join_reference: Option<Id>,
+    pub(crate) message_reference: Option<Id>,
+    pub(crate) topic_name: &'a str,
+    pub(crate) event_name: &'a str,
+    pub(crate) payload: P,
+}
+
+impl<'a, P> Push<'a, P> {
+    pub(crate) fn new(
+        join_reference: Option<Id>,
+        message_reference: Option<Id>,
+        topic_name: &'a str,
+        event_name: &'a str,
+        payload: P,
+    ) -> Self {
+        Self {
+            join_reference,
+            message_reference,
+            topic_name,
+            event_name,
+            payload,
+        }
+    }
+
+    pub(crate) fn into_err(self) -> Message<()> {
+        Message {
+            join_reference: self.join_reference.map(|id| id.to_string()),
+            message_reference: self.message_reference.map(|id| id.to_string()),
+            topic_name: self.topic_name.to_string(),
+            event_name: self.event_name.to_string(),
+            payload: (),
+        }
+    }
+}
+
+impl<P> Push<'_, P>
+where
+    P: Serialize,
+{
+    /// Encodes the message as JSON in a text frame.
+    ///
+    /// The message is serialized in the buffer, which is reused between the messages so it
+    /// doesn't grow while serializing. The frame is then copied in a single allocation of the
+    /// exact size, that is passed to tungstenite.
+    pub(crate) fn encode_json(&self, buf: &mut Vec<u8>) -> Result<Utf8Bytes, Error> {
+        buf.clear();
+
+        serde_json::to_writer(&mut *buf, self).map_err(Error::Serialize)?;
+
+        let frame = Bytes::copy_from_slice(buf);
+
+        Ok(Utf8Bytes::try_from(frame).expect("serde_json writes valid UTF-8"))
+    }
+}
+
+impl<P> Push<'_, P>
+where
+    P: AsRef<[u8]>,
+{
+    /// Encodes a push message with the Phoenix binary serializer format.
+    ///
+    /// See <https://github.com/phoenixframework/phoenix/blob/ad1a7ee2c9c29ff102b94242fdbb9cb14dd0dd4b/assets/js/phoenix/serializer.js>
+    pub(crate) fn encode_binary(&self) -> Result<Bytes, Error> {
+        let payload = self.payload.as_ref();
+
+        let references = [
+            ("join_reference", self.join_reference),
+            ("message_reference", self.message_reference),
+        ];
+        let names = [
+            ("topic_name", self.topic_name),
+            ("event_name", self.event_name),
+        ];
+
+        let len = BINARY_HEADER_LEN
+            + references
+                .iter()
+                .filter_map(|(_, id)| id.map(id_len))
+                .sum::<usize>()
+            + names.iter().map(|(_, name)| name.len()).sum::<usize>()
+            + payload.len();
+
+        let mut buf = Vec::with_capacity(len);
+
+        buf.push(BINARY_KIND_PUSH);
+
+        for (field, id) in references {
+            let len = id.map_or(0, id_len);
+
+            buf.push(u8::try_from(len).map_err(|_| Error::BinaryFieldLength(field))?);
+        }
+
+        for (field, name) in names {
+            buf.push(u8::try_from(name.len()).map_err(|_| Error::BinaryFieldLength(field))?);
+        }
+
+        for id in references.into_iter().filter_map(|(_, id)| id) {
+            write!(buf, "{id}").expect("writing to a vector cannot fail");
+        }
+
+        for (_, name) in names {
+            buf.extend_from_slice(name.as_bytes());
+        }
+
+        buf.extend_from_slice(payload);
+
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl<P> Serialize for Push<'_, P>
+where
+    P: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let Self {
+            join_reference,
+            message_reference,
+            topic_name,
+            event_name,
+            payload,
+        } = self;
+
+        (
+            join_reference.map(Reference),
+            message_reference.map(Reference),
+            topic_name,
+            event_name,
+            payload,
+        )
+            .serialize(serializer)
+    }
+}
+
+/// Serializes the reference as a string without allocating it.
+struct Reference(Id);
+
+impl Serialize for Reference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        // Formatted on the stack, faster than going through `Display`
+        let mut buf = [0; MAX_ID_LEN];
+        let mut start = buf.len();
+        let mut id = self.0;
+
+        loop {
+            start -= 1;
+            buf[start] = b'0' + (id % 10) as u8;
+            id /= 10;
+
+            if id == 0 {
+                break;
+            }
+        }
+
+        let digits = std::str::from_utf8(&buf[start..]).expect("the digits are ASCII");
+
+        serializer.serialize_str(digits)
+    }
+}
+
+/// Number of digits of the largest reference.
+const MAX_ID_LEN: usize = Id::MAX.ilog10() as usize + 1;
+
+/// Number of digits of the reference.
+fn id_len(id: Id) -> usize {
+    id.checked_ilog10().map_or(1, |digits| digits as usize + 1)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct ChannelMsg<'a, P> {
     pub(crate) join_reference: Option<Cow<'a, str>>,
@@ -115,6 +628,7 @@ pub(crate) struct ChannelMsg<'a, P> {
     pub(crate) payload: P,
 }
 
+#[cfg(test)]
 impl<'a, P> ChannelMsg<'a, P> {
     pub(crate) fn new(
         join_reference: Option<Id>,
@@ -131,16 +645,6 @@ impl<'a, P> ChannelMsg<'a, P> {
             payload,
         }
     }
-
-    pub(crate) fn into_err(self) -> Message<()> {
-        Message {
-            join_reference: self.join_reference.map(Cow::into),
-            message_reference: self.message_reference.map(Cow::into),
-            topic_name: self.topic_name.into(),
-            event_name: self.event_name.into(),
-            payload: (),
-        }
-    }
 }
 
 impl<P> Serialize for ChannelMsg<'_, P>
@@ -261,6 +765,202 @@ mod tests {
         assert_eq!(message, exp);
     }
 
+    #[test]
+    fn deserialize_reply() {
+        let ok: Reply<Map> =
+            serde_json::from_str(r#"{"status":"ok","response":{"some":"param"}}"#).unwrap();
+
+        assert_eq!(
+            ok.into_result(),
+            Ok(Map::from_iter([("some".to_string(), "param".to_string())]))
+        );
+
+        let error: Reply<Map> =
+            serde_json::from_str(r#"{"status":"error","response":{"reason":"unauthorized"}}"#)
+                .unwrap();
+
+        assert_eq!(
+            error.into_result(),
+            Err(serde_json::json!({"reason": "unauthorized"}))
+        );
+    }
+
+    #[test]
+    fn message_into_reply() {
+        let msg: Message<serde_json::Value> = serde_json::from_str::<ChannelMsg<_>>(
+            r#"["1","2","room:42","phx_reply",{"status":"error","response":{"reason":"unauthorized"}}]"#,
+        )
+        .map(Message::from)
+        .unwrap();
+
+        let Err(Error::ReplyError { msg, response }) = msg.into_reply::<Map>() else {
+            panic!("expected a reply error");
+        };
+
+        assert_eq!(msg.message_reference.as_deref(), Some("2"));
+        assert_eq!(*response, serde_json::json!({"reason": "unauthorized"}));
+    }
+
+    #[test]
+    fn into_reply_rejects_other_events() {
+        let msg: Message<serde_json::Value> = serde_json::from_str::<ChannelMsg<_>>(
+            r#"["1","2","room:42","new_msg",{"status":"ok","response":{}}]"#,
+        )
+        .map(Message::from)
+        .unwrap();
+
+        let Err(Error::NotReply(msg)) = msg.into_reply::<Map>() else {
+            panic!("expected a not reply error");
+        };
+
+        assert_eq!(msg.event_name, "new_msg");
+    }
+
+    #[test]
+    fn deserialize_raw_payload_again() {
+        let frame = r#"["1","2","room:42","new_msg",{"body":"hello","count":1}]"#;
+
+        let msg = serde_json::from_str::<RawMessage>(frame)
+            .unwrap()
+            .to_message();
+
+        assert!(msg.deserialize_payload::<Map>().is_err());
+
+        let value: Message<serde_json::Value> = msg.deserialize_payload().unwrap();
+
+        assert_eq!(value.topic_name, "room:42");
+        assert_eq!(
+            value.payload,
+            serde_json::json!({"body": "hello", "count": 1})
+        );
+    }
+
+    #[test]
+    fn raw_message_borrows_frame() {
+        let frame = RawFrame::new(Utf8Bytes::from_static(
+            r#"[null,"2","room:42","new_msg",{"body":"hello"}]"#,
+        ));
+
+        let msg = frame.message().unwrap();
+
+        assert!(matches!(msg.topic_name, Cow::Borrowed("room:42")));
+        assert!(matches!(msg.event_name, Cow::Borrowed("new_msg")));
+        assert!(msg.join_reference.is_none());
+        assert!(matches!(msg.message_reference, Some(Cow::Borrowed("2"))));
+        assert_eq!(msg.payload.get(), r#"{"body":"hello"}"#);
+
+        let payload: Map = msg.deserialize_payload().unwrap();
+
+        assert_eq!(
+            payload,
+            Map::from_iter([("body".to_string(), "hello".to_string())])
+        );
+    }
+
+    #[test]
+    fn encode_binary_push() {
+        let msg = Push::new(Some(12), Some(123), "topic", "ev", [101, 109]);
+
+        let exp = [
+            0, 2, 3, 5, 2, b'1', b'2', b'1', b'2', b'3', b't', b'o', b'p', b'i', b'c', b'e', b'v',
+            101, 109,
+        ];
+
+        assert_eq!(*msg.encode_binary().unwrap(), exp);
+
+        let long = "a".repeat(256);
+        let msg = Push::new(None, Some(1), &long, "ev", []);
+
+        assert!(matches!(
+            msg.encode_binary(),
+            Err(Error::BinaryFieldLength("topic_name"))
+        ));
+    }
+
+    #[test]
+    fn encode_json_push() {
+        let cases = [
+            (
+                Push::new(
+                    Some(0),
+                    Some(0),
+                    "miami:weather",
+                    "phx_join",
+                    Map::default(),
+                ),
+                r#"["0","0","miami:weather","phx_join",{}]"#,
+            ),
+            (
+                Push::new(None, Some(10), "phoenix", "heartbeat", Map::default()),
+                r#"[null,"10","phoenix","heartbeat",{}]"#,
+            ),
+        ];
+
+        // The same buffer is reused, while the previous frames are still alive
+        let mut buf = Vec::new();
+        let frames: Vec<_> = cases
+            .iter()
+            .map(|(msg, _)| msg.encode_json(&mut buf).unwrap())
+            .collect();
+
+        for (frame, (_, exp)) in frames.into_iter().zip(cases) {
+            assert_eq!(frame, exp);
+        }
+    }
+
+    #[test]
+    fn reference_len() {
+        assert_eq!(id_len(0), 1);
+        assert_eq!(id_len(9), 1);
+        assert_eq!(id_len(10), 2);
+        assert_eq!(id_len(usize::MAX), usize::MAX.to_string().len());
+    }
+
+    #[test]
+    fn classify_messages() {
+        let cases = [
+            (
+                r#"["1","2","room:42","phx_reply",{"status":"ok","response":{}}]"#,
+                MessageKind::Reply,
+            ),
+            (
+                r#"["1",null,"room:42","phx_error",{}]"#,
+                MessageKind::Control(ControlEvent::Error),
+            ),
+            (
+                r#"["1",null,"room:42","phx_close",{}]"#,
+                MessageKind::Control(ControlEvent::Close),
+            ),
+            (
+                r#"[null,null,"room:42","presence_diff",{"joins":{},"leaves":{}}]"#,
+                MessageKind::Control(ControlEvent::PresenceDiff),
+            ),
+            (r#"["1",null,"room:42","new_msg",{}]"#, MessageKind::Push),
+            (
+                r#"[null,null,"room:42","new_msg",{}]"#,
+                MessageKind::Broadcast,
+            ),
+        ];
+
+        for (frame, exp) in cases {
+            let msg: Message<serde_json::Value> = serde_json::from_str::<ChannelMsg<_>>(frame)
+                .map(Message::from)
+                .unwrap();
+
+            assert_eq!(msg.kind(), exp, "{frame}");
+            assert_eq!(
+                serde_json::from_str::<RawMessage>(frame).unwrap().kind(),
+                exp
+            );
+        }
+
+        assert_eq!(ControlEvent::from_event("new_msg"), None);
+        assert_eq!(
+            ControlEvent::from_event(ControlEvent::PresenceState.as_str()),
+            Some(ControlEvent::PresenceState)
+        );
+    }
+
     #[test]
     fn serialize_deserialize_join() {
         let join = r#"["0","0","miami:weather","phx_join",{"some":"param"}]"#;
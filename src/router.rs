@@ -0,0 +1,286 @@
+//! Routes the received messages to handlers by topic pattern.
+//!
+//! The topics are split in segments on `:` and the patterns support:
+//!
+//! - literal segments, like `room` in `room:lobby`, matching the same segment;
+//! - parameters, like `{id}` in `user:{id}:notifications`, matching any segment and extracting it
+//!   in the [`Params`];
+//! - wildcards `*`, matching any segment, or the remaining part of the topic if it's the last
+//!   segment of the pattern like in `room:*`.
+
+use std::fmt::Debug;
+
+use tracing::{instrument, trace, warn};
+
+use crate::message::Message;
+use crate::{Client, Error};
+
+type Handler = Box<dyn FnMut(&Params, Message<serde_json::Value>) + Send>;
+type Fallback = Box<dyn FnMut(Message<serde_json::Value>) + Send>;
+
+/// Topic pattern to register a route on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+}
+
+impl Pattern {
+    /// Parses the topic pattern.
+    pub fn new(pattern: &str) -> Self {
+        let segments = pattern
+            .split(':')
+            .map(|s| {
+                if s == "*" {
+                    Segment::Wildcard
+                } else if let Some(name) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Segment::Param(name.to_string())
+                } else {
+                    Segment::Literal(s.to_string())
+                }
+            })
+            .collect();
+
+        Self { segments }
+    }
+
+    /// Matches the topic, returning the extracted parameters.
+    pub fn matches(&self, topic: &str) -> Option<Params> {
+        let mut params = Params::default();
+        let mut topic_segments = topic.split(':');
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            let is_last = i + 1 == self.segments.len();
+
+            // A trailing wildcard matches the rest of the topic
+            if is_last && *segment == Segment::Wildcard {
+                return topic_segments.next().map(|_| params);
+            }
+
+            let value = topic_segments.next()?;
+
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.values.push((name.clone(), value.to_string())),
+                Segment::Wildcard => {}
+            }
+        }
+
+        topic_segments.next().is_none().then_some(params)
+    }
+}
+
+/// Parameters extracted from the topic by a [`Pattern`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Returns the value of the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find_map(|(n, v)| (n == name).then_some(v.as_str()))
+    }
+
+    /// Iterates the parameter names and values in the order of the pattern.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+struct Route {
+    pattern: Pattern,
+    handler: Handler,
+}
+
+/// Dispatches the messages to the handler of the first matching route.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Fallback>,
+}
+
+impl Router {
+    /// Returns a router without routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler for the topics matching the pattern.
+    ///
+    /// The routes are matched in the order they are registered.
+    #[must_use]
+    pub fn route<F>(mut self, pattern: &str, handler: F) -> Self
+    where
+        F: FnMut(&Params, Message<serde_json::Value>) + Send + 'static,
+    {
+        self.routes.push(Route {
+            pattern: Pattern::new(pattern),
+            handler: Box::new(handler),
+        });
+
+        self
+    }
+
+    /// Sets the handler for the messages not matching any route.
+    #[must_use]
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(Message<serde_json::Value>) + Send + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+
+        self
+    }
+
+    /// Dispatches the message to the matching route or to the fallback.
+    ///
+    /// Returns true if the message matched a route.
+    pub fn dispatch(&mut self, msg: Message<serde_json::Value>) -> bool {
+        let found = self
+            .routes
+            .iter_mut()
+            .find_map(|route| route.pattern.matches(&msg.topic_name).map(|p| (route, p)));
+
+        match found {
+            Some((route, params)) => {
+                trace!(topic = msg.topic_name, "message routed");
+
+                (route.handler)(&params, msg);
+
+                true
+            }
+            None => {
+                trace!(topic = msg.topic_name, "no route matched");
+
+                if let Some(fallback) = &mut self.fallback {
+                    fallback(msg);
+                }
+
+                false
+            }
+        }
+    }
+
+    /// Receives the messages from the client and dispatches them.
+    ///
+    /// Returns when the connection is closed or fails. The invalid frames are logged and skipped,
+    /// like in [`Client::run`].
+    #[instrument(skip_all)]
+    pub async fn run(&mut self, client: &Client) -> Result<(), Error> {
+        loop {
+            match client.recv().await {
+                Ok(msg) => {
+                    self.dispatch(msg);
+                }
+                Err(Error::Disconnected) => return Ok(()),
+                Err(err) if err.is_invalid_frame() => {
+                    warn!(error = %err, "skipping invalid frame");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field(
+                "routes",
+                &self.routes.iter().map(|r| &r.pattern).collect::<Vec<_>>(),
+            )
+            .field("fallback", &self.fallback.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn message(topic: &str) -> Message<serde_json::Value> {
+        Message {
+            join_reference: None,
+            message_reference: None,
+            topic_name: topic.to_string(),
+            event_name: "new_msg".to_string(),
+            payload: serde_json::Value::Null,
+        }
+    }
+
+    #[test]
+    fn pattern_matches() {
+        let room = Pattern::new("room:*");
+
+        assert!(room.matches("room:42").is_some());
+        assert!(room.matches("room:42:private").is_some());
+        assert!(room.matches("room").is_none());
+        assert!(room.matches("user:42").is_none());
+
+        let notifications = Pattern::new("user:{id}:notifications");
+
+        let params = notifications.matches("user:42:notifications").unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.iter().collect::<Vec<_>>(), [("id", "42")]);
+
+        assert!(notifications.matches("user:42").is_none());
+        assert!(notifications.matches("user:42:notifications:all").is_none());
+
+        let middle = Pattern::new("user:*:status");
+
+        assert!(middle.matches("user:42:status").is_some());
+        assert!(middle.matches("user:42:43:status").is_none());
+    }
+
+    #[test]
+    fn dispatch_routes_and_fallback() {
+        let routed = Arc::new(Mutex::new(Vec::new()));
+
+        let rooms = Arc::clone(&routed);
+        let users = Arc::clone(&routed);
+        let fallback = Arc::clone(&routed);
+
+        let mut router = Router::new()
+            .route("room:*", move |_params, msg| {
+                rooms
+                    .lock()
+                    .unwrap()
+                    .push(format!("room {}", msg.topic_name));
+            })
+            .route("user:{id}:notifications", move |params, _msg| {
+                users
+                    .lock()
+                    .unwrap()
+                    .push(format!("user {}", params.get("id").unwrap()));
+            })
+            .fallback(move |msg| {
+                fallback
+                    .lock()
+                    .unwrap()
+                    .push(format!("fallback {}", msg.topic_name));
+            });
+
+        assert!(router.dispatch(message("room:lobby")));
+        assert!(router.dispatch(message("user:42:notifications")));
+        assert!(!router.dispatch(message("phoenix")));
+
+        assert_eq!(
+            *routed.lock().unwrap(),
+            ["room room:lobby", "user 42", "fallback phoenix"]
+        );
+    }
+}
@@ -0,0 +1,731 @@
+//! Fake Phoenix endpoint to test the channel logic without a server.
+//!
+//! The [`MockServer`] runs in a task and is connected to the [`Client`] through an in-memory
+//! stream, with the same WebSocket frames of a real connection. The messages sent by the client
+//! are handled in order, so the replies are received in a deterministic order.
+//!
+//! ```
+//! # use phoenix_chan::testing::MockServer;
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), phoenix_chan::Error> {
+//! let (client, server) = MockServer::new()
+//!     .join_reply("room:lobby", Ok(serde_json::json!({"user": 42})))
+//!     .connect()
+//!     .await;
+//!
+//! client.join("room:lobby").await?;
+//!
+//! let reply = client.recv::<serde_json::Value>().await?;
+//! assert_eq!(reply.event_name, "phx_reply");
+//!
+//! server.broadcast("room:lobby", "new_msg", serde_json::json!({"body": "hi"}))?;
+//!
+//! let msg = client.recv::<serde_json::Value>().await?;
+//! assert_eq!(msg.event_name, "new_msg");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::borrow::Cow;
+use std::pin::pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tokio::TokioAdapter;
+use futures::future::Either;
+use futures::{SinkExt, StreamExt};
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tracing::{debug, trace, warn};
+use tungstenite::Utf8Bytes;
+use tungstenite::http::{HeaderMap, StatusCode};
+use tungstenite::protocol::Role;
+
+use crate::client::Handshake;
+use crate::connect::BoxIo;
+use crate::message::{ChannelMsg, Message};
+use crate::router::Pattern;
+use crate::transport::WebSocket;
+use crate::{Client, Error};
+
+/// Capacity of the in-memory stream between the client and the server.
+const BUFFER_LEN: usize = 64 * 1024;
+
+/// Handles the messages of the channels matching a topic, like a Phoenix channel module.
+///
+/// The same handler is called for all the topics matching the pattern it was registered with.
+pub trait MockChannel: Send + 'static {
+    /// Called when the client joins the topic, returns the response of the `ok` or `error` reply.
+    ///
+    /// By default the join is accepted with an empty response.
+    fn join(&mut self, socket: &mut MockSocket<'_>, payload: Value) -> Result<Value, Value> {
+        let _ = (socket, payload);
+
+        Ok(Value::Object(serde_json::Map::new()))
+    }
+
+    /// Called for the events sent by the client on the joined topic, returns the response of the
+    /// reply if any.
+    ///
+    /// By default no reply is sent.
+    fn handle_in(
+        &mut self,
+        socket: &mut MockSocket<'_>,
+        event: &str,
+        payload: Value,
+    ) -> Option<Result<Value, Value>> {
+        let _ = (socket, event, payload);
+
+        None
+    }
+}
+
+/// Channel replying to the join with a scripted response, see [`MockServer::join_reply`].
+struct JoinReply(Result<Value, Value>);
+
+impl MockChannel for JoinReply {
+    fn join(&mut self, _socket: &mut MockSocket<'_>, _payload: Value) -> Result<Value, Value> {
+        self.0.clone()
+    }
+}
+
+/// Topic of the channel handled by a [`MockChannel`].
+pub struct MockSocket<'a> {
+    topic: &'a str,
+    join_reference: Option<&'a str>,
+    pushes: Vec<Utf8Bytes>,
+}
+
+impl MockSocket<'_> {
+    /// Returns the topic of the channel.
+    pub fn topic(&self) -> &str {
+        self.topic
+    }
+
+    /// Pushes an event to the client on the topic, after the reply to the handled message.
+    pub fn push<P>(&mut self, event: &str, payload: P) -> Result<(), Error>
+    where
+        P: Serialize,
+    {
+        let frame = encode(self.join_reference, None, self.topic, event, payload)?;
+
+        self.pushes.push(frame);
+
+        Ok(())
+    }
+}
+
+/// Fake Phoenix endpoint, see the [module documentation](self).
+///
+/// The joins of the topics without a channel are rejected like Phoenix does, with the
+/// `unmatched topic` reason.
+pub struct MockServer {
+    channels: Vec<(Pattern, Box<dyn MockChannel>)>,
+    heartbeat: Duration,
+}
+
+impl std::fmt::Debug for MockServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockServer")
+            .field("heartbeat", &self.heartbeat)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockServer {
+    /// Returns a server without channels.
+    pub fn new() -> Self {
+        Self {
+            channels: Vec::new(),
+            heartbeat: crate::builder::DEFAULT_HEARTBEAT,
+        }
+    }
+
+    /// Handles the topics matching the pattern with the channel.
+    ///
+    /// The pattern is matched like the routes of a [`Router`](crate::router::Router), so `room:*`
+    /// matches all the topics with the prefix like the `channel` macro of a Phoenix socket. The
+    /// first matching channel handles the topic.
+    #[must_use]
+    pub fn channel<C>(mut self, pattern: &str, channel: C) -> Self
+    where
+        C: MockChannel,
+    {
+        self.channels
+            .push((Pattern::new(pattern), Box::new(channel)));
+
+        self
+    }
+
+    /// Replies to the joins of the topics matching the pattern with the response, ignoring the
+    /// other events.
+    ///
+    /// The join is accepted with an `ok` reply, or rejected with an `error` one.
+    #[must_use]
+    pub fn join_reply(self, pattern: &str, reply: Result<Value, Value>) -> Self {
+        self.channel(pattern, JoinReply(reply))
+    }
+
+    /// Set the heart-bit interval of the connected client.
+    #[must_use]
+    pub fn heartbeat(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+
+        self
+    }
+
+    /// Returns a client connected to the server, and the handle to drive the server.
+    ///
+    /// The server is spawned on the Tokio runtime, so it must be called from a runtime context.
+    pub async fn connect(self) -> (Client, MockHandle) {
+        let (client_io, server_io) = tokio::io::duplex(BUFFER_LEN);
+
+        let client_io: BoxIo = Box::new(TokioAdapter::new(client_io));
+        let connection = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+        let ws = WebSocketStream::from_raw_socket(TokioAdapter::new(server_io), Role::Server, None)
+            .await;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let (commands, receiver) = mpsc::unbounded_channel();
+
+        let server = Server {
+            channels: self.channels,
+            state: Arc::clone(&state),
+            ws,
+            outgoing: Vec::new(),
+        };
+
+        tokio::spawn(server.run(receiver));
+
+        let handshake = Handshake::from_parts(StatusCode::SWITCHING_PROTOCOLS, HeaderMap::new());
+
+        let client = Client::new(WebSocket::new(connection), handshake, self.heartbeat);
+
+        (client, MockHandle { state, commands })
+    }
+}
+
+/// Drives the [`MockServer`] connected to a client.
+#[derive(Debug, Clone)]
+pub struct MockHandle {
+    state: Arc<Mutex<State>>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl MockHandle {
+    /// Broadcasts an event to the client on the topic, like `Endpoint.broadcast`.
+    ///
+    /// The event is sent even if the client didn't join the topic.
+    pub fn broadcast<P>(&self, topic: &str, event: &str, payload: P) -> Result<(), Error>
+    where
+        P: Serialize,
+    {
+        let frame = encode(None, None, topic, event, payload)?;
+
+        self.send(Command::Send(frame));
+
+        Ok(())
+    }
+
+    /// Simulates a crash of the channel on the topic, sending the `phx_error` event.
+    ///
+    /// Returns false if the client didn't join the topic.
+    pub fn crash(&self, topic: &str) -> bool {
+        self.terminate(topic, "phx_error")
+    }
+
+    /// Closes the channel on the topic from the server, sending the `phx_close` event.
+    ///
+    /// Returns false if the client didn't join the topic.
+    pub fn close(&self, topic: &str) -> bool {
+        self.terminate(topic, "phx_close")
+    }
+
+    /// Closes the connection, the client receives [`Error::Disconnected`] after the messages
+    /// already sent.
+    pub fn disconnect(&self) {
+        lock(&self.state).joined.clear();
+
+        self.send(Command::Disconnect);
+    }
+
+    /// Returns true if the client joined the topic.
+    pub fn is_joined(&self, topic: &str) -> bool {
+        lock(&self.state).joined.contains_key(topic)
+    }
+
+    /// Returns the messages received from the client, heartbeats included.
+    pub fn received(&self) -> Vec<Message<Value>> {
+        lock(&self.state).received.clone()
+    }
+
+    fn terminate(&self, topic: &str, event: &str) -> bool {
+        let Some(joined) = lock(&self.state).joined.remove(topic) else {
+            return false;
+        };
+
+        if let Ok(frame) = encode(
+            joined.join_reference.as_deref(),
+            None,
+            topic,
+            event,
+            empty(),
+        ) {
+            self.send(Command::Send(frame));
+        }
+
+        true
+    }
+
+    fn send(&self, command: Command) {
+        // The server stops once disconnected
+        if self.commands.send(command).is_err() {
+            debug!("mock server disconnected, command dropped");
+        }
+    }
+}
+
+/// Command sent by the [`MockHandle`] to the server.
+#[derive(Debug)]
+enum Command {
+    /// Sends the frame to the client.
+    Send(Utf8Bytes),
+    /// Closes the connection.
+    Disconnect,
+}
+
+/// Channel joined by the client.
+#[derive(Debug)]
+struct Joined {
+    join_reference: Option<String>,
+    /// Index of the handler in the channels.
+    channel: usize,
+}
+
+/// State of the server shared with the [`MockHandle`].
+#[derive(Debug, Default)]
+struct State {
+    joined: FxHashMap<String, Joined>,
+    received: Vec<Message<Value>>,
+}
+
+/// Server side of the WebSocket, running in its own task.
+///
+/// The channels are only used by the task, so their callbacks are called without holding the
+/// lock of the shared state.
+struct Server {
+    channels: Vec<(Pattern, Box<dyn MockChannel>)>,
+    state: Arc<Mutex<State>>,
+    ws: WebSocketStream<TokioAdapter<DuplexStream>>,
+    /// Frames to send to the client after handling a message.
+    outgoing: Vec<Utf8Bytes>,
+}
+
+impl Server {
+    /// Handles the messages of the client and the commands of the handle, until disconnected.
+    async fn run(mut self, commands: mpsc::UnboundedReceiver<Command>) {
+        // None once all the handles were dropped, the server keeps replying to the client
+        let mut commands = Some(commands);
+
+        loop {
+            let command = async {
+                match &mut commands {
+                    Some(commands) => commands.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let next = match futures::future::select(self.ws.next(), pin!(command)).await {
+                Either::Left((frame, _)) => Either::Left(frame),
+                Either::Right((command, _)) => Either::Right(command),
+            };
+
+            match next {
+                Either::Left(Some(Ok(frame))) => {
+                    if let Err(err) = self.handle(frame) {
+                        warn!(error = %err, "mock server couldn't handle the message");
+                    }
+                }
+                Either::Left(Some(Err(err))) => {
+                    debug!(error = %err, "mock server connection failed");
+
+                    break;
+                }
+                // The client closed the connection
+                Either::Left(None) => break,
+                Either::Right(Some(Command::Send(frame))) => self.outgoing.push(frame),
+                Either::Right(Some(Command::Disconnect)) => {
+                    self.close().await;
+
+                    break;
+                }
+                Either::Right(None) => commands = None,
+            }
+
+            if let Err(err) = self.flush().await {
+                debug!(error = %err, "mock server couldn't send the frame");
+
+                break;
+            }
+        }
+
+        debug!("mock server disconnected");
+
+        lock(&self.state).joined.clear();
+    }
+
+    fn handle(&mut self, frame: tungstenite::Message) -> Result<(), Error> {
+        let tungstenite::Message::Text(text) = frame else {
+            debug!("ignoring non text frame");
+
+            return Ok(());
+        };
+
+        let msg: ChannelMsg<'_, Value> =
+            serde_json::from_str(text.as_str()).map_err(Error::Deserialize)?;
+        let msg = Message::from(msg);
+
+        trace!(%msg, "message received by the mock server");
+
+        lock(&self.state).received.push(msg.clone());
+
+        match (msg.topic_name.as_str(), msg.event_name.as_str()) {
+            ("phoenix", "heartbeat") => self.reply(&msg, Ok(empty())),
+            (_, "phx_join") => self.join(&msg),
+            (_, "phx_leave") => self.leave(&msg),
+            _ => self.handle_in(&msg),
+        }
+    }
+
+    fn join(&mut self, msg: &Message<Value>) -> Result<(), Error> {
+        let topic = msg.topic_name.as_str();
+
+        let Some(channel) = self
+            .channels
+            .iter()
+            .position(|(pattern, _)| pattern.matches(topic).is_some())
+        else {
+            return self.reply(msg, Err(unmatched_topic()));
+        };
+
+        let mut socket = MockSocket {
+            topic,
+            join_reference: msg.join_reference.as_deref(),
+            pushes: Vec::new(),
+        };
+
+        let reply = self.channels[channel]
+            .1
+            .join(&mut socket, msg.payload.clone());
+        let pushes = socket.pushes;
+
+        if reply.is_ok() {
+            lock(&self.state).joined.insert(
+                topic.to_string(),
+                Joined {
+                    join_reference: msg.join_reference.clone(),
+                    channel,
+                },
+            );
+        }
+
+        self.reply(msg, reply)?;
+
+        self.outgoing.extend(pushes);
+
+        Ok(())
+    }
+
+    fn leave(&mut self, msg: &Message<Value>) -> Result<(), Error> {
+        let topic = msg.topic_name.as_str();
+
+        let joined = lock(&self.state).joined.remove(topic);
+
+        let Some(joined) = joined else {
+            return self.reply(msg, Err(unmatched_topic()));
+        };
+
+        self.reply(msg, Ok(empty()))?;
+
+        let frame = encode(
+            joined.join_reference.as_deref(),
+            None,
+            topic,
+            "phx_close",
+            empty(),
+        )?;
+
+        self.outgoing.push(frame);
+
+        Ok(())
+    }
+
+    fn handle_in(&mut self, msg: &Message<Value>) -> Result<(), Error> {
+        let topic = msg.topic_name.as_str();
+
+        let joined = lock(&self.state)
+            .joined
+            .get(topic)
+            .map(|joined| (joined.join_reference.clone(), joined.channel));
+
+        let Some((join_reference, channel)) = joined else {
+            return self.reply(msg, Err(unmatched_topic()));
+        };
+
+        let mut socket = MockSocket {
+            topic,
+            join_reference: join_reference.as_deref(),
+            pushes: Vec::new(),
+        };
+
+        let reply =
+            self.channels[channel]
+                .1
+                .handle_in(&mut socket, &msg.event_name, msg.payload.clone());
+        let pushes = socket.pushes;
+
+        if let Some(reply) = reply {
+            self.reply(msg, reply)?;
+        }
+
+        self.outgoing.extend(pushes);
+
+        Ok(())
+    }
+
+    fn reply(&mut self, msg: &Message<Value>, reply: Result<Value, Value>) -> Result<(), Error> {
+        let frame = encode_reply(msg, reply)?;
+
+        self.outgoing.push(frame);
+
+        Ok(())
+    }
+
+    /// Sends the outgoing frames to the client.
+    async fn flush(&mut self) -> Result<(), tungstenite::Error> {
+        for frame in self.outgoing.drain(..) {
+            self.ws.feed(tungstenite::Message::Text(frame)).await?;
+        }
+
+        self.ws.flush().await
+    }
+
+    /// Closes the WebSocket, waiting for the client to acknowledge it.
+    ///
+    /// The frames sent before are still received by the client.
+    async fn close(&mut self) {
+        if let Err(err) = self.flush().await {
+            debug!(error = %err, "mock server couldn't send the frame");
+
+            return;
+        }
+
+        if let Err(err) = self.ws.close(None).await {
+            debug!(error = %err, "mock server couldn't close the connection");
+
+            return;
+        }
+
+        // The messages received while closing are discarded
+        while let Some(Ok(_)) = self.ws.next().await {}
+    }
+}
+
+fn encode<P>(
+    join_reference: Option<&str>,
+    message_reference: Option<&str>,
+    topic: &str,
+    event: &str,
+    payload: P,
+) -> Result<Utf8Bytes, Error>
+where
+    P: Serialize,
+{
+    let msg = ChannelMsg {
+        join_reference: join_reference.map(Cow::Borrowed),
+        message_reference: message_reference.map(Cow::Borrowed),
+        topic_name: Cow::Borrowed(topic),
+        event_name: Cow::Borrowed(event),
+        payload,
+    };
+
+    serde_json::to_string(&msg)
+        .map(Utf8Bytes::from)
+        .map_err(Error::Serialize)
+}
+
+fn encode_reply(msg: &Message<Value>, reply: Result<Value, Value>) -> Result<Utf8Bytes, Error> {
+    let payload = match reply {
+        Ok(response) => serde_json::json!({"status": "ok", "response": response}),
+        Err(response) => serde_json::json!({"status": "error", "response": response}),
+    };
+
+    encode(
+        msg.join_reference.as_deref(),
+        msg.message_reference.as_deref(),
+        &msg.topic_name,
+        "phx_reply",
+        payload,
+    )
+}
+
+fn empty() -> Value {
+    Value::Object(serde_json::Map::new())
+}
+
+fn unmatched_topic() -> Value {
+    serde_json::json!({"reason": "unmatched topic"})
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use crate::message::MessageKind;
+
+    use super::*;
+
+    struct Room;
+
+    impl MockChannel for Room {
+        fn handle_in(
+            &mut self,
+            socket: &mut MockSocket<'_>,
+            event: &str,
+            payload: Value,
+        ) -> Option<Result<Value, Value>> {
+            match event {
+                "ping" => Some(Ok(payload)),
+                "shout" => {
+                    socket.push("shout", payload).unwrap();
+
+                    None
+                }
+                _ => Some(Err(json!({"reason": "unknown event"}))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_channel_events() {
+        let (client, server) = MockServer::new()
+            .channel("room:*", Room)
+            .join_reply("private:*", Err(json!({"reason": "unauthorized"})))
+            .connect()
+            .await;
+
+        client.join("private:1").await.unwrap();
+        let reply = client.recv::<Value>().await.unwrap().into_reply::<Value>();
+
+        assert!(matches!(reply, Err(Error::ReplyError { .. })));
+        assert!(!server.is_joined("private:1"));
+
+        client.join("room:1").await.unwrap();
+        client.recv::<Value>().await.unwrap();
+
+        assert!(server.is_joined("room:1"));
+
+        let id = client
+            .send("room:1", "ping", json!({"n": 1}))
+            .await
+            .unwrap();
+        let reply = client.recv::<Value>().await.unwrap();
+
+        assert_eq!(reply.message_reference, Some(id.to_string()));
+        assert_eq!(
+            reply.into_reply::<Value>().unwrap().payload,
+            json!({"n": 1})
+        );
+
+        client.send("room:1", "shout", json!("hi")).await.unwrap();
+        let push = client.recv::<Value>().await.unwrap();
+
+        assert_eq!(push.kind(), MessageKind::Push);
+        assert_eq!(
+            (push.event_name.as_str(), push.payload),
+            ("shout", json!("hi"))
+        );
+
+        server.broadcast("room:2", "news", json!({})).unwrap();
+        assert_eq!(
+            client.recv::<Value>().await.unwrap().kind(),
+            MessageKind::Broadcast
+        );
+
+        assert!(server.crash("room:1"));
+        let error = client.recv::<Value>().await.unwrap();
+
+        assert_eq!(error.event_name, "phx_error");
+        assert!(!server.is_joined("room:1"));
+
+        server.disconnect();
+        assert!(matches!(
+            client.recv::<Value>().await,
+            Err(Error::Disconnected)
+        ));
+
+        let events = server
+            .received()
+            .into_iter()
+            .map(|msg| msg.event_name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(events, ["phx_join", "phx_join", "ping", "shout"]);
+    }
+
+    /// Channel using the handle of the server while handling the messages.
+    struct Inspect(Arc<OnceLock<MockHandle>>);
+
+    impl MockChannel for Inspect {
+        fn handle_in(
+            &mut self,
+            socket: &mut MockSocket<'_>,
+            _event: &str,
+            _payload: Value,
+        ) -> Option<Result<Value, Value>> {
+            let handle = self.0.get().unwrap();
+
+            Some(Ok(json!({"joined": handle.is_joined(socket.topic())})))
+        }
+    }
+
+    #[tokio::test]
+    async fn use_the_handle_in_the_channel() {
+        let handle = Arc::new(OnceLock::new());
+
+        let (client, server) = MockServer::new()
+            .channel("room:*", Inspect(Arc::clone(&handle)))
+            .connect()
+            .await;
+
+        handle.set(server).unwrap();
+
+        client.join("room:1").await.unwrap();
+        client.recv::<Value>().await.unwrap();
+
+        client.send("room:1", "inspect", ()).await.unwrap();
+        let reply = client.recv::<Value>().await.unwrap();
+
+        assert_eq!(
+            reply.into_reply::<Value>().unwrap().payload,
+            json!({"joined": true})
+        );
+    }
+}
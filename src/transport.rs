@@ -0,0 +1,110 @@
+//! Transports carrying the frames between the [`Client`](crate::Client) and the server.
+//!
+//! The client encodes and decodes the messages, and sends the heartbeats, while the transport only
+//! moves the frames. The WebSocket is the default transport, the long-poll one is used when the
+//! upgrade is blocked.
+
+use std::fmt::Debug;
+
+use async_tungstenite::{WebSocketReceiver, WebSocketSender, WebSocketStream};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::Error;
+use crate::connect::BoxIo;
+
+/// Sends and receives the frames of the connection.
+///
+/// The methods can be called concurrently, a frame can be sent while another task is waiting to
+/// receive one.
+///
+/// The futures must be cancel safe, since the client drops them when the task receiving the
+/// messages is cancelled: a frame already read by a dropped [`recv`](Transport::recv) must be
+/// returned by the next call, and a frame passed to a dropped [`send`](Transport::send) can be
+/// sent or discarded, but not sent partially.
+pub(crate) trait Transport: Debug + Send + Sync {
+    /// Sends the frame to the server.
+    fn send(&self, frame: tungstenite::Message) -> BoxFuture<'_, Result<(), SendError>>;
+
+    /// Returns the next frame, or [`None`] if disconnected.
+    fn recv(&self) -> BoxFuture<'_, Option<Result<tungstenite::Message, Error>>>;
+
+    /// Closes the connection with the server.
+    fn close(&self) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+/// Error returned by [`Transport::send`].
+#[derive(Debug)]
+pub(crate) enum SendError {
+    /// The WebSocket couldn't send the frame, returned with the message in [`Error::Send`].
+    WebSocket(Box<tungstenite::Error>),
+    /// The transport failed.
+    Transport(Error),
+}
+
+impl From<Error> for SendError {
+    fn from(value: Error) -> Self {
+        SendError::Transport(value)
+    }
+}
+
+/// Transport over a WebSocket.
+#[derive(Debug)]
+pub(crate) struct WebSocket {
+    sender: Mutex<WebSocketSender<BoxIo>>,
+    receiver: Mutex<WebSocketReceiver<BoxIo>>,
+}
+
+impl WebSocket {
+    pub(crate) fn new(connection: WebSocketStream<BoxIo>) -> Self {
+        let (sender, receiver) = connection.split();
+
+        Self {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl Transport for WebSocket {
+    fn send(&self, frame: tungstenite::Message) -> BoxFuture<'_, Result<(), SendError>> {
+        async move {
+            self.sender
+                .lock()
+                .await
+                .send(frame)
+                .await
+                .map_err(|err| SendError::WebSocket(Box::new(err)))
+        }
+        .boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<Result<tungstenite::Message, Error>>> {
+        async move {
+            self.receiver
+                .lock()
+                .await
+                .next()
+                .await
+                .map(|res| res.map_err(Box::new).map_err(Error::Recv))
+        }
+        .boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, Result<(), Error>> {
+        async move {
+            debug!("closing the WebSocket");
+
+            self.sender
+                .lock()
+                .await
+                .close(None)
+                .await
+                .map_err(Box::new)
+                .map_err(Error::Close)
+        }
+        .boxed()
+    }
+}
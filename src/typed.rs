@@ -0,0 +1,444 @@
+//! Channels declared once with their topic, join parameters and events.
+//!
+//! Implement [`ChannelSpec`] to bundle the types of a channel and join it with
+//! [`Client::join_channel`]. The returned [`TypedChannel`] only pushes the outgoing events and
+//! returns the incoming events of the spec, so a mismatched event is a compile error.
+//!
+//! ```no_run
+//! # use phoenix_chan::Error;
+//! # use phoenix_chan::event::{FromEvent, IntoEvent};
+//! # use phoenix_chan::typed::ChannelSpec;
+//! struct NewMsg {
+//!     body: String,
+//! }
+//! # impl IntoEvent for NewMsg {
+//! #     fn into_event(self) -> Result<(&'static str, serde_json::Value), serde_json::Error> {
+//! #         Ok(("new_msg", serde_json::json!({"body": self.body})))
+//! #     }
+//! # }
+//! # impl FromEvent for NewMsg {
+//! #     fn from_event(event: &str, payload: serde_json::Value) -> Result<Self, Error> {
+//! #         match (event, payload["body"].as_str()) {
+//! #             ("new_msg", Some(body)) => Ok(Self { body: body.to_string() }),
+//! #             _ => Err(Error::UnknownEvent(event.to_string())),
+//! #         }
+//! #     }
+//! # }
+//!
+//! struct Room {
+//!     id: u32,
+//! }
+//!
+//! impl ChannelSpec for Room {
+//!     type JoinParams = ();
+//!     type JoinReply = serde_json::Value;
+//!     type In = NewMsg;
+//!     type Out = NewMsg;
+//!
+//!     fn topic(&self) -> String {
+//!         format!("room:{}", self.id)
+//!     }
+//! }
+//!
+//! # async fn example(client: phoenix_chan::Client) -> Result<(), Error> {
+//! let mut room = client.join_channel(Room { id: 42 }, ()).await?;
+//!
+//! room.push(NewMsg { body: "hello".to_string() }).await?;
+//!
+//! let msg = room.recv().await?;
+//! println!("{}", msg.payload.body);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Pushing the event of another channel doesn't compile:
+//!
+//! ```compile_fail
+//! # use phoenix_chan::Error;
+//! # use phoenix_chan::event::{FromEvent, IntoEvent};
+//! # use phoenix_chan::typed::ChannelSpec;
+//! struct NewMsg {
+//!     body: String,
+//! }
+//! # impl IntoEvent for NewMsg {
+//! #     fn into_event(self) -> Result<(&'static str, serde_json::Value), serde_json::Error> {
+//! #         Ok(("new_msg", serde_json::json!({"body": self.body})))
+//! #     }
+//! # }
+//! # impl FromEvent for NewMsg {
+//! #     fn from_event(event: &str, payload: serde_json::Value) -> Result<Self, Error> {
+//! #         match (event, payload["body"].as_str()) {
+//! #             ("new_msg", Some(body)) => Ok(Self { body: body.to_string() }),
+//! #             _ => Err(Error::UnknownEvent(event.to_string())),
+//! #         }
+//! #     }
+//! # }
+//!
+//! struct Room {
+//!     id: u32,
+//! }
+//!
+//! impl ChannelSpec for Room {
+//!     type JoinParams = ();
+//!     type JoinReply = serde_json::Value;
+//!     type In = NewMsg;
+//!     type Out = NewMsg;
+//!
+//!     fn topic(&self) -> String {
+//!         format!("room:{}", self.id)
+//!     }
+//! }
+//!
+//! struct Typing;
+//! # impl IntoEvent for Typing {
+//! #     fn into_event(self) -> Result<(&'static str, serde_json::Value), serde_json::Error> {
+//! #         Ok(("typing", serde_json::json!({})))
+//! #     }
+//! # }
+//!
+//! # async fn example(client: phoenix_chan::Client) -> Result<(), Error> {
+//! let room = client.join_channel(Room { id: 42 }, ()).await?;
+//!
+//! room.push(Typing).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tracing::{debug, instrument, trace, warn};
+
+use crate::channel::DEFAULT_JOIN_TIMEOUT;
+use crate::client::Id;
+use crate::dispatch::{Filter, Subscription};
+use crate::event::{FromEvent, IntoEvent};
+use crate::message::{ControlEvent, Message, MessageKind};
+use crate::{Client, Error};
+
+/// Declaration of a channel, bundling the topic with the types of its messages.
+pub trait ChannelSpec {
+    /// Parameters sent with the join.
+    type JoinParams: Serialize;
+    /// Response of the successful join reply.
+    type JoinReply: DeserializeOwned;
+    /// Events received on the channel.
+    type In: FromEvent;
+    /// Events pushed on the channel.
+    type Out: IntoEvent;
+
+    /// Returns the topic to join, with the parameters of the topic pattern filled in.
+    fn topic(&self) -> String;
+}
+
+/// Channel joined with [`Client::join_channel`].
+///
+/// The messages are received through a [`Subscription`], so a task must be receiving from the
+/// client, for example with [`Client::run`].
+#[derive(Debug)]
+pub struct TypedChannel<'a, C>
+where
+    C: ChannelSpec,
+{
+    client: &'a Client,
+    spec: C,
+    topic: String,
+    reply: C::JoinReply,
+    subscription: Subscription,
+}
+
+impl<'a, C> TypedChannel<'a, C>
+where
+    C: ChannelSpec,
+{
+    #[instrument(skip_all)]
+    pub(crate) async fn join(
+        client: &'a Client,
+        spec: C,
+        params: C::JoinParams,
+    ) -> Result<Self, Error> {
+        let topic = spec.topic();
+
+        // Subscribe before joining to not miss the reply
+        let mut subscription = client.subscribe(Filter::topic(&topic));
+
+        let join_id = client.join_with_payload(&topic, params).await?;
+        let join_ref = join_id.to_string();
+
+        let wait_reply = async {
+            loop {
+                let msg = subscription.recv().await.ok_or(Error::Disconnected)?;
+
+                if msg.kind() != MessageKind::Reply
+                    || msg.message_reference.as_ref() != Some(&join_ref)
+                {
+                    trace!(message = msg.info(), "skipping message before join");
+
+                    continue;
+                }
+
+                break msg.into_reply::<C::JoinReply>();
+            }
+        };
+
+        let reply = match tokio::time::timeout(DEFAULT_JOIN_TIMEOUT, wait_reply).await {
+            Ok(reply) => reply?,
+            Err(_elapsed) => {
+                warn!(timeout = ?DEFAULT_JOIN_TIMEOUT, "join timed out");
+
+                // Like phoenix.js, leave so the server doesn't keep a join the client gave up
+                client.leave(&topic).await?;
+
+                return Err(Error::JoinTimeout {
+                    topic,
+                    timeout: DEFAULT_JOIN_TIMEOUT,
+                });
+            }
+        };
+
+        debug!(topic, "channel joined");
+
+        Ok(Self {
+            client,
+            spec,
+            topic,
+            reply: reply.payload,
+            subscription,
+        })
+    }
+
+    /// Returns the spec of the channel.
+    pub fn spec(&self) -> &C {
+        &self.spec
+    }
+
+    /// Returns the joined topic.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Returns the response of the join reply.
+    pub fn join_reply(&self) -> &C::JoinReply {
+        &self.reply
+    }
+
+    /// Pushes an event on the channel.
+    pub async fn push(&self, event: C::Out) -> Result<Id, Error> {
+        self.client.send_event(&self.topic, event).await
+    }
+
+    /// Returns the next event received on the channel.
+    ///
+    /// The replies to the pushed events are skipped. Returns [`Error::ChannelClosed`] if the
+    /// channel crashed or was closed by the server.
+    pub async fn recv(&mut self) -> Result<Message<C::In>, Error> {
+        loop {
+            let msg = self.subscription.recv().await.ok_or(Error::Disconnected)?;
+
+            if let Some(msg) = decode(msg)? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Leaves the channel.
+    pub async fn leave(self) -> Result<Id, Error> {
+        self.client.leave(&self.topic).await
+    }
+}
+
+/// Decodes a message received on the channel, returns [`None`] for the replies.
+fn decode<E>(msg: Message<serde_json::Value>) -> Result<Option<Message<E>>, Error>
+where
+    E: FromEvent,
+{
+    match msg.kind() {
+        MessageKind::Reply => {
+            trace!(message = msg.info(), "skipping reply");
+
+            Ok(None)
+        }
+        MessageKind::Control(event @ (ControlEvent::Error | ControlEvent::Close)) => {
+            Err(Error::ChannelClosed {
+                topic: msg.topic_name,
+                event,
+            })
+        }
+        _ => msg.into_event().map(Some),
+    }
+}
+
+#[cfg(all(test, feature = "derive", feature = "testing"))]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::PhoenixEvent;
+    use crate::testing::{MockChannel, MockServer, MockSocket};
+
+    #[derive(Debug, PartialEq, PhoenixEvent)]
+    enum RoomEvent {
+        #[event("new_msg")]
+        NewMsg { body: String },
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct RoomReply {
+        user: u32,
+    }
+
+    struct Room {
+        id: u32,
+    }
+
+    impl ChannelSpec for Room {
+        type JoinParams = serde_json::Value;
+        type JoinReply = RoomReply;
+        type In = RoomEvent;
+        type Out = RoomEvent;
+
+        fn topic(&self) -> String {
+            format!("room:{}", self.id)
+        }
+    }
+
+    /// Replies to the pushed messages and echoes them back.
+    struct Echo;
+
+    impl MockChannel for Echo {
+        fn join(
+            &mut self,
+            _socket: &mut MockSocket<'_>,
+            payload: serde_json::Value,
+        ) -> Result<serde_json::Value, serde_json::Value> {
+            Ok(payload)
+        }
+
+        fn handle_in(
+            &mut self,
+            socket: &mut MockSocket<'_>,
+            event: &str,
+            payload: serde_json::Value,
+        ) -> Option<Result<serde_json::Value, serde_json::Value>> {
+            socket.push(event, payload).unwrap();
+
+            Some(Ok(json!({})))
+        }
+    }
+
+    fn message(event: &str, payload: serde_json::Value) -> Message<serde_json::Value> {
+        Message {
+            join_reference: Some("1".to_string()),
+            message_reference: None,
+            topic_name: "room:lobby".to_string(),
+            event_name: event.to_string(),
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn join_push_and_recv() {
+        let (client, server) = MockServer::new().channel("room:*", Echo).connect().await;
+
+        let script = async {
+            let mut room = client
+                .join_channel(Room { id: 42 }, json!({"user": 7}))
+                .await
+                .unwrap();
+
+            assert_eq!(room.topic(), "room:42");
+            assert_eq!(*room.join_reply(), RoomReply { user: 7 });
+
+            room.push(RoomEvent::NewMsg {
+                body: "hello".to_string(),
+            })
+            .await
+            .unwrap();
+
+            // The reply to the push is skipped
+            let echo = room.recv().await.unwrap();
+
+            assert_eq!(echo.event_name, "new_msg");
+            assert_eq!(
+                echo.payload,
+                RoomEvent::NewMsg {
+                    body: "hello".to_string()
+                }
+            );
+
+            server
+                .broadcast("room:42", "new_msg", json!({"body": "hi"}))
+                .unwrap();
+
+            assert_eq!(
+                room.recv().await.unwrap().payload,
+                RoomEvent::NewMsg {
+                    body: "hi".to_string()
+                }
+            );
+
+            assert!(server.close("room:42"));
+
+            assert!(matches!(
+                room.recv().await,
+                Err(Error::ChannelClosed {
+                    event: ControlEvent::Close,
+                    ..
+                })
+            ));
+        };
+
+        tokio::select! {
+            res = client.run() => panic!("client stopped: {res:?}"),
+            () = script => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn join_rejected() {
+        let (client, _server) = MockServer::new()
+            .join_reply("room:*", Err(json!({"reason": "unauthorized"})))
+            .connect()
+            .await;
+
+        let join = tokio::select! {
+            res = client.run() => panic!("client stopped: {res:?}"),
+            join = client.join_channel(Room { id: 42 }, json!({})) => join,
+        };
+
+        let Err(Error::ReplyError { response, .. }) = join else {
+            panic!("expected a reply error");
+        };
+
+        assert_eq!(*response, json!({"reason": "unauthorized"}));
+    }
+
+    #[test]
+    fn decode_channel_messages() {
+        let msg = decode::<RoomEvent>(message("new_msg", json!({"body": "hello"})))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            msg.payload,
+            RoomEvent::NewMsg {
+                body: "hello".to_string()
+            }
+        );
+
+        let reply = message("phx_reply", json!({"status": "ok"}));
+
+        assert!(decode::<RoomEvent>(reply).unwrap().is_none());
+
+        let closed = decode::<RoomEvent>(message("phx_close", json!({})));
+
+        assert!(matches!(
+            closed,
+            Err(Error::ChannelClosed {
+                event: ControlEvent::Close,
+                ..
+            })
+        ));
+    }
+}